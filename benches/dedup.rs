@@ -0,0 +1,103 @@
+//! Inserting extents from several threads into a single `Mutex<HashSet>`, as the dedup set
+//! used to be, against spreading them over 64 sets by `disk_bytenr` as the extent set of a scan
+//! does. That set is private to the scan, so its sharding is mirrored here.
+//!
+//! Besides the time taken, the lock acquisitions that had to wait for another thread are
+//! counted: `cargo bench --bench dedup`. `RAYON_NUM_THREADS` sets the number of threads.
+use std::{
+    collections::HashSet,
+    env,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex, MutexGuard, TryLockError,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+/// Extents inserted by every thread, every fourth of them inserted by all threads.
+const EXTENTS: u64 = 1 << 20;
+const SHARDS: usize = 64;
+const RUNS: usize = 5;
+
+/// Lock `mutex`, counting in `contended` if it was held by another thread.
+fn lock<'a, T>(mutex: &'a Mutex<T>, contended: &AtomicUsize) -> MutexGuard<'a, T> {
+    match mutex.try_lock() {
+        Ok(guard) => guard,
+        Err(TryLockError::WouldBlock) => {
+            contended.fetch_add(1, Ordering::Relaxed);
+            mutex.lock().unwrap()
+        }
+        Err(TryLockError::Poisoned(err)) => panic!("{err}"),
+    }
+}
+
+/// Insert the extents of `threads` threads with `insert`, returning the time taken
+/// and how many of them were new.
+fn run(threads: u64, insert: impl Fn(u64) -> bool + Sync) -> (Duration, usize) {
+    let new = AtomicUsize::new(0);
+    let start = Instant::now();
+    thread::scope(|scope| {
+        for t in 0..threads {
+            let (insert, new) = (&insert, &new);
+            scope.spawn(move || {
+                for i in 0..EXTENTS {
+                    // extents are sector aligned
+                    let extent = if i % 4 == 0 { 0 } else { t };
+                    if insert((i * threads + extent) << 12) {
+                        new.fetch_add(1, Ordering::Relaxed);
+                    }
+                }
+            });
+        }
+    });
+    (start.elapsed(), new.into_inner())
+}
+
+/// Run a fresh set made by `set` [RUNS] times, printing the fastest run and the contention.
+fn bench<S: Sync>(
+    name: &str,
+    threads: u64,
+    set: impl Fn() -> S,
+    insert: impl Fn(&S, u64, &AtomicUsize) -> bool + Sync,
+) {
+    let mut min = Duration::MAX;
+    let mut contended = 0;
+    for _ in 0..RUNS {
+        let set = set();
+        let waits = AtomicUsize::new(0);
+        let (elapsed, new) = run(threads, |bytenr| insert(&set, bytenr, &waits));
+        let expected = EXTENTS * threads * 3 / 4 + EXTENTS / 4;
+        assert_eq!(new as u64, expected, "{name} deduplicated wrong");
+        min = min.min(elapsed);
+        contended += waits.into_inner();
+    }
+    println!(
+        "{name:<10} min {min:>10.2?}  contended locks {:>10} per run",
+        contended / RUNS
+    );
+}
+
+fn main() {
+    let threads = env::var("RAYON_NUM_THREADS")
+        .ok()
+        .and_then(|threads| threads.parse().ok())
+        .unwrap_or_else(|| thread::available_parallelism().map_or(4, |n| n.get() as u64));
+    println!("{} extents from {threads} threads", EXTENTS * threads);
+    bench(
+        "single",
+        threads,
+        || Mutex::new(HashSet::new()),
+        |set, bytenr, waits| lock(set, waits).insert(bytenr),
+    );
+    bench(
+        "sharded",
+        threads,
+        || -> [Mutex<HashSet<u64>>; SHARDS] { std::array::from_fn(|_| Mutex::default()) },
+        |shards, bytenr, waits| {
+            // the same shard as the scan picks, above the always zero sector offset bits
+            let shard = (bytenr >> 12) as usize % SHARDS;
+            lock(&shards[shard], waits).insert(bytenr)
+        },
+    );
+}
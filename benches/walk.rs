@@ -0,0 +1,100 @@
+//! Walking a deep tree with [compviz::count_files], which spreads the entries of each directory
+//! over the pool with `par_bridge`, against spawning a rayon task per entry as the walk used to.
+//!
+//! Only the walk is timed, no extents are read, so it runs on any filesystem:
+//! `cargo bench --bench walk`. The tree is built in the temp dir, `TMPDIR` picks another one,
+//! and both walks use the default thread count unless `RAYON_NUM_THREADS` is set.
+use std::{
+    env, fs, io,
+    path::Path,
+    process,
+    sync::atomic::{AtomicUsize, Ordering},
+    time::{Duration, Instant},
+};
+
+use compviz::AnalyzeOptions;
+
+/// Subdirectories of every directory above the deepest level.
+const FANOUT: usize = 4;
+/// Levels of directories below the root.
+const DEPTH: u32 = 6;
+/// Files in every directory.
+const FILES: usize = 8;
+const RUNS: usize = 10;
+
+fn build(dir: &Path, depth: u32) -> io::Result<()> {
+    fs::create_dir(dir)?;
+    for i in 0..FILES {
+        fs::write(dir.join(format!("file{i}")), b"")?;
+    }
+    if depth < DEPTH {
+        for i in 0..FANOUT {
+            build(&dir.join(format!("dir{i}")), depth + 1)?;
+        }
+    }
+    Ok(())
+}
+
+/// The walk as it was, a task for every entry of every directory.
+fn spawn_per_entry<'s>(scope: &rayon::Scope<'s>, path: &Path, files: &'s AtomicUsize) {
+    for entry in fs::read_dir(path).unwrap() {
+        let entry = entry.unwrap();
+        scope.spawn(move |scope| {
+            let file_type = entry.file_type().unwrap();
+            if file_type.is_dir() {
+                spawn_per_entry(scope, &entry.path(), files);
+            } else if file_type.is_file() {
+                files.fetch_add(1, Ordering::Relaxed);
+            }
+        });
+    }
+}
+
+/// Run `walk` [RUNS] times after a warm-up, printing the fastest and the mean run.
+fn bench(name: &str, expected: usize, mut walk: impl FnMut() -> usize) {
+    assert_eq!(walk(), expected, "{name} counted wrong");
+    let runs: Vec<Duration> = (0..RUNS)
+        .map(|_| {
+            let start = Instant::now();
+            walk();
+            start.elapsed()
+        })
+        .collect();
+    let min = runs.iter().min().unwrap();
+    let mean = runs.iter().sum::<Duration>() / RUNS as u32;
+    println!("{name:<16} min {min:>10.2?}  mean {mean:>10.2?}");
+}
+
+fn main() {
+    let root = env::temp_dir().join(format!("compviz-bench-walk-{}", process::id()));
+    build(&root, 0).unwrap();
+    let dirs: usize = (0..=DEPTH).map(|level| FANOUT.pow(level)).sum();
+    let expected = dirs * FILES;
+    let threads = env::var("RAYON_NUM_THREADS")
+        .ok()
+        .and_then(|threads| threads.parse().ok())
+        .unwrap_or_else(compviz::default_num_threads);
+    println!("{expected} files in {dirs} directories, {threads} threads");
+
+    let paths = [root.clone()];
+    let opts = AnalyzeOptions {
+        threads: Some(threads),
+        // plain files on whatever filesystem the temp dir is
+        force: true,
+        ..Default::default()
+    };
+    bench("par_bridge", expected, || {
+        compviz::count_files(&paths, &opts).unwrap()
+    });
+    let pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(threads)
+        .build()
+        .unwrap();
+    bench("spawn per entry", expected, || {
+        let files = AtomicUsize::new(0);
+        pool.scope(|scope| spawn_per_entry(scope, &root, &files));
+        files.into_inner()
+    });
+
+    fs::remove_dir_all(&root).unwrap();
+}
@@ -2,6 +2,11 @@ use std::env;
 use std::path::PathBuf;
 
 fn main() {
+    // The btrfs headers only exist on Linux, the crate itself fails with a clear error elsewhere.
+    if env::var("CARGO_CFG_TARGET_OS").as_deref() != Ok("linux") {
+        return;
+    }
+
     // Tell cargo to look for shared libraries in the specified directory
     println!("cargo:rustc-link-search=/path/to/lib");
 
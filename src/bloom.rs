@@ -0,0 +1,63 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// A bloom filter of `u64`s over a fixed number of bits, shared between threads without locks.
+///
+/// Sized by memory rather than by the number of items it's going to hold, so it never grows.
+/// Once it holds more items than it was tuned for, its false positive rate just goes up.
+#[derive(Debug)]
+pub(crate) struct BloomFilter {
+    bits: Box<[AtomicU64]>,
+    /// Number of bits set per item.
+    hashes: u64,
+}
+impl BloomFilter {
+    /// A filter of `bytes` bytes, with as many hashes as a false positive rate of `fp_rate`
+    /// takes at the optimal load, i.e. `-log2(fp_rate)`.
+    pub fn new(bytes: usize, fp_rate: f64) -> Self {
+        Self {
+            bits: (0..(bytes / 8).max(1)).map(|_| AtomicU64::new(0)).collect(),
+            hashes: (-fp_rate.log2()).ceil().max(1.0) as u64,
+        }
+    }
+    /// Bytes taken by the bits.
+    pub fn memory(&self) -> usize {
+        self.bits.len() * size_of::<AtomicU64>()
+    }
+    fn n_bits(&self) -> u64 {
+        self.bits.len() as u64 * 64
+    }
+    /// Insert `key`, returning whether it wasn't in the filter before.
+    ///
+    /// False negatives are impossible, but a new key may be taken for one inserted
+    /// already at the [Self::false_positive_rate]. Two threads inserting the same key at
+    /// the same time may both see it as new.
+    pub fn insert(&self, key: u64) -> bool {
+        // double hashing, the bit positions are h1 + i * h2
+        let h1 = splitmix64(key);
+        let h2 = splitmix64(h1) | 1;
+        let mut new = false;
+        for i in 0..self.hashes {
+            let bit = h1.wrapping_add(i.wrapping_mul(h2)) % self.n_bits();
+            let mask = 1 << (bit % 64);
+            let prev = self.bits[(bit / 64) as usize].fetch_or(mask, Ordering::Relaxed);
+            new |= prev & mask == 0;
+        }
+        new
+    }
+    /// The chance of a key not inserted yet to be taken as inserted, given how full it is now.
+    pub fn false_positive_rate(&self) -> f64 {
+        let set: u64 = self
+            .bits
+            .iter()
+            .map(|word| word.load(Ordering::Relaxed).count_ones() as u64)
+            .sum();
+        (set as f64 / self.n_bits() as f64).powi(self.hashes as i32)
+    }
+}
+
+pub(crate) fn splitmix64(x: u64) -> u64 {
+    let mut z = x.wrapping_add(0x9e3779b97f4a7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+    z ^ (z >> 31)
+}
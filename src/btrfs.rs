@@ -1,11 +1,54 @@
+//! Enumerating the file extents of a btrfs file through the `BTRFS_IOC_TREE_SEARCH_V2` ioctl.
+//!
+//! Open the file, build [SearchArgs] for its inode number and iterate:
+//!
+//! ```no_run
+//! use std::{fs::File, os::unix::fs::MetadataExt};
+//!
+//! use compviz::btrfs::{get_file_extents_with, SearchArgs};
+//!
+//! let file = File::open("/mnt/data/file")?;
+//! let ino = file.metadata()?.ino();
+//! let mut args = SearchArgs::new_search_file_extent_data(ino, 0, SearchArgs::DEFAULT_BUF_SIZE);
+//! for extent in &mut get_file_extents_with(file, &mut args)? {
+//!     let extent = extent?;
+//!     match extent.disk_bytenr() {
+//!         // the data is stored in the item itself, disk_num_bytes() is its size there
+//!         None => println!("inline, {} of {} bytes", extent.disk_num_bytes(), extent.num_bytes()),
+//!         Some(0) => println!("hole of {} bytes", extent.num_bytes()),
+//!         Some(bytenr) => println!(
+//!             "{} bytes referencing {} bytes at {bytenr}",
+//!             extent.num_bytes(),
+//!             extent.disk_num_bytes()
+//!         ),
+//!     }
+//! }
+//! # Ok::<(), Box<dyn std::error::Error>>(())
+//! ```
 use core::fmt;
-use std::{cell::RefCell, io, os::fd::AsRawFd};
+use std::{
+    ffi::CString,
+    fs::File,
+    io,
+    mem::MaybeUninit,
+    ops::RangeInclusive,
+    os::{fd::AsRawFd, unix::ffi::OsStrExt},
+    path::Path,
+};
 
 use libc::ioctl;
 
-pub use crate::ffi::btrfs_ioctl_search_args_v2_64KB;
-use crate::ffi::*;
+pub use crate::ffi::{
+    btrfs_file_extent_item, btrfs_ioctl_search_header, btrfs_ioctl_search_key,
+    BTRFS_EXTENT_DATA_KEY, BTRFS_INODE_ITEM_KEY, BTRFS_INODE_REF_KEY, BTRFS_XATTR_ITEM_KEY,
+};
+use crate::{ffi::*, CompvizError};
 
+/// Size of a search header followed by a non-inline file extent item.
+const BUF_ITEM_SIZE: usize =
+    size_of::<btrfs_ioctl_search_header>() + size_of::<btrfs_file_extent_item>();
+
+/// `btrfs_file_extent_item::type`.
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
 pub enum BtrfsFileExtentType {
     Inline = 0,
@@ -23,40 +66,37 @@ impl From<u8> for BtrfsFileExtentType {
         }
     }
 }
+/// A file extent item as found by the search, copied out of the buffer of the [SearchArgs].
+///
+/// The buffer is reused, or even reallocated, once the iterator runs out of items in it,
+/// so items don't point into it.
 #[derive(Debug)]
 pub struct BtrfsFileExtentItem<'a> {
-    ptr: *const btrfs_file_extent_item,
+    item: btrfs_file_extent_item,
     len: usize,
-    read: RefCell<Option<btrfs_file_extent_item>>,
     phantom: std::marker::PhantomData<&'a ()>,
 }
 impl BtrfsFileExtentItem<'_> {
     const INLINE_DATA_OFFSET: usize = std::mem::offset_of!(btrfs_file_extent_item, disk_bytenr);
-    fn ensure_read(&self) -> &Self {
-        self.read
-            .borrow_mut()
-            .get_or_insert_with(|| unsafe { self.ptr.read_unaligned() });
-        self
-    }
     #[inline]
     pub fn generation(&self) -> u64 {
-        self.ensure_read().read.borrow().unwrap().generation
+        self.item.generation
     }
     #[inline]
     pub fn ram_bytes(&self) -> u64 {
-        self.ensure_read().read.borrow().unwrap().ram_bytes
+        self.item.ram_bytes
     }
     #[inline]
     pub fn compression(&self) -> u8 {
-        self.ensure_read().read.borrow().unwrap().compression
+        self.item.compression
     }
     #[inline]
     pub fn encryption(&self) -> u8 {
-        self.ensure_read().read.borrow().unwrap().encryption
+        self.item.encryption
     }
     #[inline]
     pub fn type_(&self) -> BtrfsFileExtentType {
-        self.ensure_read().read.borrow().unwrap().type_.into()
+        self.item.type_.into()
     }
     /// Only non-inline extent has this field.  
     /// For inline extent, this is None.
@@ -64,16 +104,19 @@ impl BtrfsFileExtentItem<'_> {
     pub fn disk_bytenr(&self) -> Option<u64> {
         match self.type_() {
             BtrfsFileExtentType::Inline => None,
-            _ => Some(self.ensure_read().read.borrow().unwrap().disk_bytenr),
+            _ => Some(self.item.disk_bytenr),
         }
     }
     #[inline]
     /// Only non-inline extent has this field.  
-    /// For inline extent, this is calculated from `metadata length - previous meaningful fields`
+    /// For inline extent, this is calculated from `metadata length - previous meaningful fields`,
+    /// i.e. the length of the data following the header, compressed if the extent is.
+    /// So it's below [Self::ram_bytes] for compressed inline extents, same as compsize reports.
+    /// Items are packed in the leaf, their length has no padding to exclude.
     pub fn disk_num_bytes(&self) -> u64 {
         match self.type_() {
-            BtrfsFileExtentType::Inline => (self.len - Self::INLINE_DATA_OFFSET) as u64,
-            _ => self.ensure_read().read.borrow().unwrap().disk_num_bytes,
+            BtrfsFileExtentType::Inline => self.len.saturating_sub(Self::INLINE_DATA_OFFSET) as u64,
+            _ => self.item.disk_num_bytes,
         }
     }
     /// Only non-inline extent has this field.  
@@ -82,7 +125,7 @@ impl BtrfsFileExtentItem<'_> {
     pub fn offset(&self) -> Option<u64> {
         match self.type_() {
             BtrfsFileExtentType::Inline => None,
-            _ => Some(self.ensure_read().read.borrow().unwrap().offset),
+            _ => Some(self.item.offset),
         }
     }
     /// Only non-inline extent has this field.  
@@ -91,58 +134,140 @@ impl BtrfsFileExtentItem<'_> {
     pub fn num_bytes(&self) -> u64 {
         match self.type_() {
             BtrfsFileExtentType::Inline => self.ram_bytes(),
-            _ => self.ensure_read().read.borrow().unwrap().num_bytes,
+            _ => self.item.num_bytes,
         }
     }
 }
 
 impl fmt::Display for BtrfsFileExtentItem<'_> {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{:?}", self.ensure_read().read.borrow(),)
+        write!(f, "{:?}", self.item)
     }
 }
-pub struct BtrfsFileExtentIterator<'a> {
-    fd: std::fs::File,
-    args: &'a mut btrfs_ioctl_search_args_v2_64KB,
-    buf_offset: isize,
+/// Runs a tree search, i.e. `BTRFS_IOC_TREE_SEARCH_V2`.
+///
+/// [File] does it with the ioctl on the file, other implementations may
+/// fill the buffer with canned items instead, e.g. to test parsing them.
+pub trait TreeSearch {
+    /// Search for the items described by `args.key()`, filling the buffer with
+    /// as many of them as fit, each a [btrfs_ioctl_search_header] followed by the item,
+    /// and setting `nr_items` to their number.
+    fn search(&mut self, args: &mut SearchArgs) -> Result<(), CompvizError>;
 }
-impl<'a> Iterator for &mut BtrfsFileExtentIterator<'a> {
-    type Item = Result<BtrfsFileExtentItem<'a>, std::io::Error>;
-    fn next(&mut self) -> Option<Self::Item> {
-        if self.buf_offset < 0 {
+impl TreeSearch for File {
+    fn search(&mut self, args: &mut SearchArgs) -> Result<(), CompvizError> {
+        loop {
             let ret = unsafe {
-                // SAFETY: self.args and self.fd are valid as long as self is alive
+                // SAFETY: args and self are valid for the duration of the call
                 ioctl(
-                    self.fd.as_raw_fd(),
+                    self.as_raw_fd(),
                     BTRFS_IOC_TREE_SEARCH_V2_ULONG,
-                    &(*self.args),
+                    args.as_mut_ptr(),
                 )
             };
-            if ret < 0 {
-                return Some(Err(io::Error::last_os_error()));
+            if ret >= 0 {
+                return Ok(());
+            }
+            if let Some(err) = search_error(io::Error::last_os_error()) {
+                return Err(err);
+            }
+        }
+    }
+}
+/// What a failed search ioctl amounts to, [None] if it's to be retried.
+fn search_error(err: io::Error) -> Option<CompvizError> {
+    match err.raw_os_error() {
+        // a signal arrived before the search finished, the args are untouched so just retry
+        Some(libc::EINTR) => None,
+        // what the ioctl fails with on any other filesystem
+        Some(libc::ENOTTY) => Some(CompvizError::NotBtrfs),
+        _ => Some(err.into()),
+    }
+}
+
+/// The file extents of a file in offset order, see [get_file_extents_with].
+///
+/// The [Iterator] is implemented for `&mut` of it, so iterate `&mut iter`.
+/// It searches again whenever the buffer of the [SearchArgs] is used up.
+pub struct BtrfsFileExtentIterator<'a, S: TreeSearch = File> {
+    searcher: S,
+    args: &'a mut SearchArgs,
+    buf_offset: isize,
+}
+impl<S: TreeSearch> BtrfsFileExtentIterator<'_, S> {
+    /// Search once, growing the buffer if not even the first item fit into it, e.g. a large
+    /// inline extent with a small buffer. The larger buffer is then kept for later searches.
+    fn search(&mut self) -> Result<(), CompvizError> {
+        match self.searcher.search(self.args) {
+            // the kernel reports the size needed for the item in place of the buffer size
+            Err(CompvizError::Io(err)) if err.raw_os_error() == Some(libc::EOVERFLOW) => {
+                let needed = self.args.header().buf_size as usize;
+                if needed <= self.args.buf_size() || needed > SearchArgs::MAX_BUF_SIZE {
+                    return Err(CompvizError::Io(err));
+                }
+                self.args.resize(needed);
+                self.searcher.search(self.args)
+            }
+            result => result,
+        }
+    }
+    /// Fail if the batch just searched starts at or before the last item of the previous one,
+    /// i.e. before the min key [SearchArgs::resume_after] moved past it. Those items were
+    /// yielded already, and searching on would find them again, forever.
+    fn check_progress(&mut self) -> Result<(), CompvizError> {
+        if self.args.key().nr_items == 0 {
+            return Ok(());
+        }
+        let first = unsafe {
+            // SAFETY: the buffer holds at least one header, as nr_items says
+            self.args
+                .buf_mut_ptr()
+                .cast::<btrfs_ioctl_search_header>()
+                .read_unaligned()
+        };
+        let key = self.args.key();
+        if (first.objectid, first.type_, first.offset)
+            < (key.min_objectid, key.min_type, key.min_offset)
+        {
+            return Err(CompvizError::Io(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "tree search returned items before its min key",
+            )));
+        }
+        Ok(())
+    }
+}
+impl<'a, S: TreeSearch> Iterator for &mut BtrfsFileExtentIterator<'a, S> {
+    type Item = Result<BtrfsFileExtentItem<'a>, CompvizError>;
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.buf_offset < 0 {
+            if let Err(err) = self.search() {
+                return Some(Err(err));
             }
             self.buf_offset = 0;
+            if let Err(err) = self.check_progress() {
+                // end the iteration, rather than searching again on the next call
+                self.args.key_mut().nr_items = 0;
+                return Some(Err(err));
+            }
         }
         let bp = unsafe {
             // SAFETY:
             // self.args.buf is valid as long as self is alive
             // ioctl won't destroy the buffer anyway
-            self.args.buf.as_mut_ptr().byte_offset(self.buf_offset)
+            self.args.buf_mut_ptr().byte_offset(self.buf_offset)
         };
-        if self.args.key.nr_items == 0 {
+        if self.args.key().nr_items == 0 {
             return None;
         }
 
         let (head, extent_item) = unsafe {
-            let head = bp
-                .cast::<btrfs_ioctl_search_header>()
-                .as_ref()
-                .unwrap_unchecked();
+            // Only the first header is aligned, as the buffer is. The kernel packs items back to
+            // back, so after an 85 bytes item the next header is at an odd offset. The header is
+            // hence copied out unaligned, while the item is packed and so of alignment 1 anyway.
+            let head = bp.cast::<btrfs_ioctl_search_header>().read_unaligned();
             let bp = bp.byte_add(size_of::<btrfs_ioctl_search_header>());
-            let extent_item = bp
-                .cast::<btrfs_file_extent_item>()
-                .as_ref()
-                .unwrap_unchecked();
+            let extent_item = bp.cast::<btrfs_file_extent_item>();
             // set the offset to next item
             //
             // Actually, there is no need to read and follow the `head.len` field,
@@ -155,9 +280,10 @@ impl<'a> Iterator for &mut BtrfsFileExtentIterator<'a> {
             (head, extent_item)
         };
         // nr_items minus one
-        self.args.key.nr_items -= 1;
-        if self.args.key.nr_items == 0 {
-            let unused_size = self.args.buf_size as usize - self.buf_offset as usize;
+        self.args.key_mut().nr_items -= 1;
+        if self.args.key().nr_items == 0 {
+            // the allocated size, the kernel only ever changes the `buf_size` field on EOVERFLOW
+            let unused_size = self.args.buf_size() - self.buf_offset as usize;
             // normally an item is of 85 bytes(32 header + 53 file_extent_item), for non-inline file.
             // It may be longer if the extent item has inline data, but in that case the file would have only one extent so it's okay.
             // So if the unused_size is less than that,
@@ -165,87 +291,560 @@ impl<'a> Iterator for &mut BtrfsFileExtentIterator<'a> {
             // (even though it could be of the case where the buffer is just used up exactly).
             //
             // You may wonder why `ioctl` call does not return an EOVERFLOW?
-            // That is returned only when the buffer is too small to hold even one item(<85 bytes).
+            // That is returned only when the buffer is too small to hold even one item(<85 bytes),
+            // and then handled by growing the buffer in `search`.
             // Or else the ioctl call succeeds and the kernel fills the buffer with as many items as it can,
             // and stops when the buffer is full.
-            const BUF_ITEM_SIZE: usize = std::mem::size_of::<btrfs_ioctl_search_header>()
-                + std::mem::size_of::<btrfs_file_extent_item>();
-            if unused_size < BUF_ITEM_SIZE {
+            // If the last item has the largest possible key there's nothing left to search.
+            if unused_size < BUF_ITEM_SIZE && self.args.resume_after(head) {
                 // set buf offset to -1 so that the next iteration will call ioctl again
                 self.buf_offset = -1;
-                // set the offset to search for subsequent items
-                self.args.key.min_offset = head.offset + 1;
                 // reset the number of items to search
-                self.args.key.nr_items = u32::MAX;
+                self.args.key_mut().nr_items = u32::MAX;
             }
         }
 
         Some(Ok(BtrfsFileExtentItem {
-            ptr: extent_item,
+            // SAFETY: the item is within the buffer, which outlives this call
+            item: unsafe { extent_item.read_unaligned() },
             len: head.len as usize,
-            read: RefCell::new(None),
             phantom: std::marker::PhantomData,
         }))
     }
 }
 
-/// It's the users' responsibility to pass the `args` as the struct is quite large.  
-/// So it's the user to determine whether to reuse args if a large amount of files are to be searched.
-pub fn get_file_extents_with(
-    fd: std::fs::File,
-    args: &mut btrfs_ioctl_search_args_v2_64KB,
-) -> Result<BtrfsFileExtentIterator<'_>, std::io::Error> {
+/// Iterate the extents `args` searches for with `searcher`,
+/// for a [File] within the subvolume of that file.
+///
+/// It's the users' responsibility to pass the `args` as the buffer may be quite large.  
+/// So it's the user to determine whether to reuse args if a large amount of files are to be searched,
+/// by resetting them with [SearchArgs::set_search_file_extent_data] for every file:
+///
+/// ```no_run
+/// use std::{fs::File, os::unix::fs::MetadataExt};
+///
+/// use compviz::btrfs::{get_file_extents_with, SearchArgs};
+///
+/// let mut args = SearchArgs::new_search_file_extent_data(0, 0, SearchArgs::DEFAULT_BUF_SIZE);
+/// for path in ["/mnt/data/a", "/mnt/data/b"] {
+///     let file = File::open(path)?;
+///     args.set_search_file_extent_data(file.metadata()?.ino(), 0);
+///     let mut extents = 0;
+///     for extent in &mut get_file_extents_with(file, &mut args)? {
+///         extent?;
+///         extents += 1;
+///     }
+///     println!("{path}: {extents} extents");
+/// }
+/// # Ok::<(), Box<dyn std::error::Error>>(())
+/// ```
+pub fn get_file_extents_with<S: TreeSearch>(
+    searcher: S,
+    args: &mut SearchArgs,
+) -> Result<BtrfsFileExtentIterator<'_, S>, CompvizError> {
     Ok(BtrfsFileExtentIterator {
-        fd,
+        searcher,
         args,
         buf_offset: -1,
     })
 }
 
-impl btrfs_ioctl_search_args_v2_64KB {
-    /// Create [btrfs_ioctl_search_args_v2_64KB] with the fixed buffer and buffer size,
+/// [btrfs_ioctl_search_args_v2] followed by a heap allocated result buffer of a runtime size.
+///
+/// A larger buffer takes fewer ioctl round-trips for files with many extents,
+/// a smaller one saves memory as every thread of a scan keeps its own.
+pub struct SearchArgs {
+    /// The args header and the buffer in one allocation, as u64s so the header is aligned.
+    mem: Box<[u64]>,
+}
+impl SearchArgs {
+    /// The buffer size used unless another one is asked for.
+    pub const DEFAULT_BUF_SIZE: usize = 64 * 1024;
+    /// The smallest buffer that fits a non-inline extent item,
+    /// the kernel fails the search with EOVERFLOW if not even one item fits.
+    pub const MIN_BUF_SIZE: usize = BUF_ITEM_SIZE;
+    /// The kernel's limit, larger buffers are rejected with EINVAL.
+    pub const MAX_BUF_SIZE: usize = 16 * 1024 * 1024;
+    const HEADER_SIZE: usize = size_of::<btrfs_ioctl_search_args_v2>();
+
+    /// Create [SearchArgs] with a `buf_size` bytes buffer,
     /// max and min object id set to the given ino, and min/max type set to [BTRFS_EXTENT_DATA_KEY],
     /// leaving all other max/min fieldsset to their extremum.
     ///
     /// This is ideal for searching all extents of a file by its inode number.
+    /// The search runs in the subvolume of the fd passed to [get_file_extents_with],
+    /// so `ino` must be the inode number of that fd's file.
+    ///
+    /// `buf_size` should be within [Self::MIN_BUF_SIZE] and [Self::MAX_BUF_SIZE].
+    ///
+    /// With a `min_transid` above 0, only items in tree blocks written at or after that
+    /// transaction are found. Such a block may still hold older extents next to the new ones,
+    /// so compare [BtrfsFileExtentItem::generation] too if only newer extents are wanted.
+    pub fn new_search_file_extent_data(ino: u64, min_transid: u64, buf_size: usize) -> SearchArgs {
+        Self::new_search_inode_items(
+            ino,
+            BTRFS_EXTENT_DATA_KEY..=BTRFS_EXTENT_DATA_KEY,
+            min_transid,
+            buf_size,
+        )
+    }
+    /// mutate self.key to as if like a newly created [SearchArgs] from [SearchArgs::new_search_file_extent_data]
+    pub fn set_search_file_extent_data(&mut self, ino: u64, min_transid: u64) {
+        self.set_search_inode_items(
+            ino,
+            BTRFS_EXTENT_DATA_KEY..=BTRFS_EXTENT_DATA_KEY,
+            min_transid,
+        );
+    }
+    /// Like [Self::new_search_file_extent_data], but for the items of the inode with key types
+    /// in `types`, e.g. [BTRFS_INODE_REF_KEY] to find the names of the file.
+    ///
+    /// Items are returned ordered by type, then offset. [BtrfsFileExtentIterator] parses
+    /// every item as a file extent item, so other types need their own parsing of the buffer
+    /// after a [TreeSearch::search].
+    pub fn new_search_inode_items(
+        ino: u64,
+        types: RangeInclusive<u32>,
+        min_transid: u64,
+        buf_size: usize,
+    ) -> SearchArgs {
+        let mut args = SearchArgs {
+            mem: vec![0; (Self::HEADER_SIZE + buf_size).div_ceil(size_of::<u64>())]
+                .into_boxed_slice(),
+        };
+        args.set_search_inode_items(ino, types, min_transid);
+        args
+    }
+    /// mutate self.key to as if like a newly created [SearchArgs] from [SearchArgs::new_search_inode_items]
+    pub fn set_search_inode_items(
+        &mut self,
+        ino: u64,
+        types: RangeInclusive<u32>,
+        min_transid: u64,
+    ) {
+        let buf_size = self.buf_size() as u64;
+        let header = self.header_mut();
+        header.buf_size = buf_size;
+        header.key = btrfs_ioctl_search_key {
+            // 0 means the subvolume the searched fd belongs to. As every file is searched
+            // through its own fd, files in nested subvolumes are looked up in their own tree,
+            // whose inode numbers may collide with those of the parent subvolume.
+            tree_id: 0,
+            max_objectid: ino,
+            min_objectid: ino,
+            min_offset: u64::MIN,
+            max_offset: u64::MAX,
+            min_transid,
+            max_transid: u64::MAX,
+            min_type: *types.start(),
+            max_type: *types.end(),
+            nr_items: u32::MAX,
+
+            unused: 0,
+            unused1: 0,
+            unused2: 0,
+            unused3: 0,
+            unused4: 0,
+        };
+    }
+    /// Move the start of the search to the key right after the one of `last`,
+    /// false if there is no such key or it wouldn't make any progress.
+    ///
+    /// The kernel compares the whole (objectid, type, offset) key against the min key,
+    /// the individual min fields don't bound the others. So the full key of the last item
+    /// is carried over rather than just its offset increased, then it won't skip or repeat
+    /// items however wide the searched key range is.
+    fn resume_after(&mut self, last: btrfs_ioctl_search_header) -> bool {
+        let (objectid, type_, offset) = if let Some(offset) = last.offset.checked_add(1) {
+            (last.objectid, last.type_, offset)
+        } else if last.type_ < u8::MAX as u32 {
+            // the key type is a u8 on disk, the kernel truncates larger ones
+            (last.objectid, last.type_ + 1, 0)
+        } else if let Some(objectid) = last.objectid.checked_add(1) {
+            (objectid, 0, 0)
+        } else {
+            return false;
+        };
+        let key = self.key_mut();
+        // The kernel only returns items at or after the min key, so the next one is always past it.
+        // Should it ever return an item before, searching again would find it again, forever.
+        if (objectid, type_, offset) <= (key.min_objectid, key.min_type, key.min_offset) {
+            return false;
+        }
+        key.min_objectid = objectid;
+        key.min_type = type_;
+        key.min_offset = offset;
+        true
+    }
+    /// Reallocate the result buffer to hold `buf_size` bytes, keeping the search key.
+    pub fn resize(&mut self, buf_size: usize) {
+        let key = *self.key();
+        self.mem =
+            vec![0; (Self::HEADER_SIZE + buf_size).div_ceil(size_of::<u64>())].into_boxed_slice();
+        let buf_size = self.buf_size() as u64;
+        let header = self.header_mut();
+        header.key = key;
+        header.buf_size = buf_size;
+    }
+    /// The allocated size of the result buffer in bytes.
     #[inline]
-    pub fn new_search_file_extent_data(ino: u64) -> btrfs_ioctl_search_args_v2_64KB {
-        btrfs_ioctl_search_args_v2_64KB {
-            buf: [0; 65536],
-            buf_size: 65536,
-            key: btrfs_ioctl_search_key {
-                tree_id: 0,
-                max_objectid: ino,
-                min_objectid: ino,
-                min_offset: u64::MIN,
-                max_offset: u64::MAX,
-                min_transid: u64::MIN,
-                max_transid: u64::MAX,
-                // Only search for EXTENT_DATA_KEY
-                min_type: BTRFS_EXTENT_DATA_KEY,
-                max_type: BTRFS_EXTENT_DATA_KEY,
-                nr_items: u32::MAX,
-
-                unused: 0,
-                unused1: 0,
-                unused2: 0,
-                unused3: 0,
-                unused4: 0,
-            },
-        }
-    }
-    /// mutate self.key to as if like a newly created [btrfs_ioctl_search_args_v2_64KB] from [btrfs_ioctl_search_args_v2_64KB::new_search_file_extent_data]
-    pub fn set_search_file_extent_data(&mut self, ino: u64) {
-        self.buf_size = 65536;
-        self.key.tree_id = 0;
-        self.key.max_objectid = ino;
-        self.key.min_objectid = ino;
-        self.key.min_offset = u64::MIN;
-        self.key.max_offset = u64::MAX;
-        self.key.min_transid = u64::MIN;
-        self.key.max_transid = u64::MAX;
-        self.key.min_type = BTRFS_EXTENT_DATA_KEY;
-        self.key.max_type = BTRFS_EXTENT_DATA_KEY;
-        self.key.nr_items = u32::MAX;
+    pub fn buf_size(&self) -> usize {
+        self.mem.len() * size_of::<u64>() - Self::HEADER_SIZE
+    }
+    #[inline]
+    pub fn key(&self) -> &btrfs_ioctl_search_key {
+        &self.header().key
+    }
+    #[inline]
+    pub fn key_mut(&mut self) -> &mut btrfs_ioctl_search_key {
+        &mut self.header_mut().key
+    }
+    fn header(&self) -> &btrfs_ioctl_search_args_v2 {
+        unsafe {
+            // SAFETY: mem is at least HEADER_SIZE long and u64 aligned, the header is plain old data
+            &*self.mem.as_ptr().cast()
+        }
+    }
+    fn header_mut(&mut self) -> &mut btrfs_ioctl_search_args_v2 {
+        unsafe {
+            // SAFETY: see header
+            &mut *self.mem.as_mut_ptr().cast()
+        }
+    }
+    /// The result buffer, e.g. for a [TreeSearch] to fill.
+    pub fn buf_mut(&mut self) -> &mut [u8] {
+        let len = self.buf_size();
+        unsafe {
+            // SAFETY: the buffer is len bytes following the header within mem, any bytes are valid u64s
+            std::slice::from_raw_parts_mut(self.buf_mut_ptr(), len)
+        }
+    }
+    fn as_mut_ptr(&mut self) -> *mut btrfs_ioctl_search_args_v2 {
+        self.mem.as_mut_ptr().cast()
+    }
+    fn buf_mut_ptr(&mut self) -> *mut u8 {
+        unsafe {
+            // SAFETY: the buffer directly follows the header within mem
+            self.mem.as_mut_ptr().cast::<u8>().add(Self::HEADER_SIZE)
+        }
+    }
+}
+impl fmt::Debug for SearchArgs {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SearchArgs")
+            .field("key", self.key())
+            .field("buf_size", &self.buf_size())
+            .finish_non_exhaustive()
+    }
+}
+
+/// Check whether `path` resides on a btrfs filesystem, by the `f_type` magic reported by `statfs(2)`.
+pub fn is_btrfs(path: impl AsRef<Path>) -> Result<bool, CompvizError> {
+    let path = CString::new(path.as_ref().as_os_str().as_bytes()).map_err(io::Error::from)?;
+    let mut buf = MaybeUninit::<libc::statfs>::uninit();
+    let ret = unsafe {
+        // SAFETY: path is a valid C string and buf is large enough to hold a statfs
+        libc::statfs(path.as_ptr(), buf.as_mut_ptr())
+    };
+    if ret < 0 {
+        return Err(io::Error::last_os_error().into());
+    }
+    // SAFETY: statfs succeeded, so buf is initialized
+    let buf = unsafe { buf.assume_init() };
+    Ok(buf.f_type == libc::BTRFS_SUPER_MAGIC)
+}
+
+/// Get the current generation (transid) of the filesystem `fd` lives on.
+///
+/// Requires a kernel supporting `BTRFS_FS_INFO_FLAG_GENERATION` (5.10+), older ones leave it unset.
+pub fn get_fs_generation(fd: &impl AsRawFd) -> Result<u64, CompvizError> {
+    let mut args = unsafe {
+        // SAFETY: btrfs_ioctl_fs_info_args is plain old data
+        std::mem::zeroed::<btrfs_ioctl_fs_info_args>()
+    };
+    args.flags = BTRFS_FS_INFO_FLAG_GENERATION as u64;
+    let ret = unsafe {
+        // SAFETY: args is valid for the duration of the call
+        ioctl(fd.as_raw_fd(), BTRFS_IOC_FS_INFO_ULONG, &mut args)
+    };
+    if ret < 0 {
+        return Err(io::Error::last_os_error().into());
+    }
+    if args.flags & BTRFS_FS_INFO_FLAG_GENERATION as u64 == 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "kernel doesn't report the filesystem generation",
+        )
+        .into());
+    }
+    Ok(args.generation)
+}
+
+/// A tree search faked in memory, for the tests here and of what reads the extents.
+#[cfg(test)]
+pub(crate) mod fake {
+    use super::*;
+
+    pub(crate) const INO: u64 = 257;
+    /// The extent at file offset `n` of the fake tree is at `DISK_START + n` on disk.
+    pub(crate) const DISK_START: u64 = 1 << 20;
+
+    /// An item of the fake tree, `len` bytes of `extent` followed by zeros.
+    pub(crate) struct FakeItem {
+        key: (u64, u32, u64),
+        pub(crate) extent: btrfs_file_extent_item,
+        pub(crate) len: usize,
+    }
+    pub(crate) fn regular(offset: u64) -> FakeItem {
+        let mut extent = unsafe {
+            // SAFETY: btrfs_file_extent_item is plain old data
+            std::mem::zeroed::<btrfs_file_extent_item>()
+        };
+        extent.ram_bytes = 4096;
+        extent.type_ = BtrfsFileExtentType::Regular as u8;
+        extent.disk_bytenr = DISK_START + offset;
+        extent.disk_num_bytes = 4096;
+        extent.num_bytes = 4096;
+        FakeItem {
+            key: (INO, BTRFS_EXTENT_DATA_KEY, offset),
+            extent,
+            len: size_of::<btrfs_file_extent_item>(),
+        }
+    }
+
+    /// An inline extent of `data_len` bytes holding `ram_bytes` once decompressed.
+    pub(crate) fn inline(ram_bytes: u64, compression: u8, data_len: usize) -> FakeItem {
+        let mut extent = unsafe {
+            // SAFETY: btrfs_file_extent_item is plain old data
+            std::mem::zeroed::<btrfs_file_extent_item>()
+        };
+        extent.ram_bytes = ram_bytes;
+        extent.compression = compression;
+        extent.type_ = BtrfsFileExtentType::Inline as u8;
+        FakeItem {
+            key: (INO, BTRFS_EXTENT_DATA_KEY, 0),
+            extent,
+            len: BtrfsFileExtentItem::INLINE_DATA_OFFSET + data_len,
+        }
+    }
+
+    /// A tree searched in memory, packing the items from the min key on into the buffer
+    /// as the kernel does.
+    #[derive(Default)]
+    pub(crate) struct FakeTree {
+        pub(crate) items: Vec<FakeItem>,
+        pub(crate) searches: usize,
+        /// Return the items from the start on every search, like a broken kernel would.
+        pub(crate) ignore_min_key: bool,
+    }
+    impl TreeSearch for &mut FakeTree {
+        fn search(&mut self, args: &mut SearchArgs) -> Result<(), CompvizError> {
+            self.searches += 1;
+            let key = *args.key();
+            let keys = (key.min_objectid, key.min_type, key.min_offset)
+                ..=(key.max_objectid, key.max_type, key.max_offset);
+            let buf_size = args.header().buf_size as usize;
+            let (mut offset, mut nr_items) = (0, 0);
+            for item in &self.items {
+                if !self.ignore_min_key && !keys.contains(&item.key) {
+                    continue;
+                }
+                let size = size_of::<btrfs_ioctl_search_header>() + item.len;
+                if offset + size > buf_size {
+                    if nr_items == 0 {
+                        args.header_mut().buf_size = size as u64;
+                        return Err(io::Error::from_raw_os_error(libc::EOVERFLOW).into());
+                    }
+                    break;
+                }
+                let header = btrfs_ioctl_search_header {
+                    transid: 0,
+                    objectid: item.key.0,
+                    offset: item.key.2,
+                    type_: item.key.1,
+                    len: item.len as u32,
+                };
+                let buf = &mut args.buf_mut()[offset..offset + size];
+                buf.fill(0);
+                unsafe {
+                    // SAFETY: buf is long enough for the header and the item, both plain old data
+                    buf.as_mut_ptr()
+                        .cast::<btrfs_ioctl_search_header>()
+                        .write_unaligned(header);
+                    buf.as_mut_ptr()
+                        .add(size_of::<btrfs_ioctl_search_header>())
+                        .cast::<btrfs_file_extent_item>()
+                        .write_unaligned(item.extent);
+                }
+                offset += size;
+                nr_items += 1;
+            }
+            args.key_mut().nr_items = nr_items;
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{fake::*, *};
+
+    /// The file offsets of the extents found, told by their disk position.
+    fn offsets(tree: &mut FakeTree, buf_size: usize) -> Vec<u64> {
+        let mut args = SearchArgs::new_search_file_extent_data(INO, 0, buf_size);
+        let mut iter = get_file_extents_with(tree, &mut args).unwrap();
+        (&mut iter)
+            .map(|item| item.unwrap().disk_bytenr().unwrap() - DISK_START)
+            .collect()
+    }
+
+    #[test]
+    fn items_split_across_batches() {
+        let mut tree = FakeTree {
+            items: (0..10).map(|i| regular(i * 4096)).collect(),
+            ..Default::default()
+        };
+        let expected: Vec<_> = (0..10).map(|i| i * 4096).collect();
+        assert_eq!(offsets(&mut tree, 3 * BUF_ITEM_SIZE), expected);
+        // three full batches and one with the last item
+        assert_eq!(tree.searches, 4);
+    }
+
+    #[test]
+    fn resume_after_carries_the_full_key() {
+        let mut args = SearchArgs::new_search_inode_items(
+            INO,
+            BTRFS_INODE_ITEM_KEY..=BTRFS_XATTR_ITEM_KEY,
+            0,
+            SearchArgs::DEFAULT_BUF_SIZE,
+        );
+        let header = |objectid, type_, offset| btrfs_ioctl_search_header {
+            transid: 0,
+            objectid,
+            offset,
+            type_,
+            len: 0,
+        };
+        let min_key = |args: &SearchArgs| {
+            let key = args.key();
+            (key.min_objectid, key.min_type, key.min_offset)
+        };
+
+        assert!(args.resume_after(header(INO, BTRFS_INODE_ITEM_KEY, 0)));
+        assert_eq!(min_key(&args), (INO, BTRFS_INODE_ITEM_KEY, 1));
+        // the offset rolls over into the next type
+        assert!(args.resume_after(header(INO, BTRFS_INODE_REF_KEY, u64::MAX)));
+        assert_eq!(min_key(&args), (INO, BTRFS_INODE_REF_KEY + 1, 0));
+        // and the type into the next objectid
+        assert!(args.resume_after(header(INO, u8::MAX as u32, u64::MAX)));
+        assert_eq!(min_key(&args), (INO + 1, 0, 0));
+        // nothing follows the largest key
+        assert!(!args.resume_after(header(u64::MAX, u8::MAX as u32, u64::MAX)));
+        assert_eq!(min_key(&args), (INO + 1, 0, 0));
+    }
+
+    #[test]
+    fn stops_when_a_search_makes_no_progress() {
+        let mut tree = FakeTree {
+            items: vec![regular(0), regular(4096)],
+            ignore_min_key: true,
+            ..Default::default()
+        };
+        // the buffer is full after both, so it searches again and gets the same two,
+        // which fail rather than being yielded again
+        let mut args = SearchArgs::new_search_file_extent_data(INO, 0, 2 * BUF_ITEM_SIZE);
+        let mut iter = get_file_extents_with(&mut tree, &mut args).unwrap();
+        let mut next = || {
+            (&mut iter)
+                .next()
+                .map(|item| item.map(|item| item.disk_bytenr().unwrap() - DISK_START))
+        };
+        assert_eq!(next().unwrap().unwrap(), 0);
+        assert_eq!(next().unwrap().unwrap(), 4096);
+        assert!(matches!(next(), Some(Err(CompvizError::Io(_)))));
+        assert!(next().is_none());
+        assert_eq!(tree.searches, 2);
+    }
+
+    #[test]
+    fn grows_the_buffer_on_overflow() {
+        let mut tree = FakeTree {
+            items: vec![inline(4000, 0, 4000)],
+            ..Default::default()
+        };
+        let mut args = SearchArgs::new_search_file_extent_data(INO, 0, SearchArgs::MIN_BUF_SIZE);
+        let items: Vec<_> = (&mut get_file_extents_with(&mut tree, &mut args).unwrap())
+            .map(Result::unwrap)
+            .map(|item| item.disk_num_bytes())
+            .collect();
+        assert_eq!(items, [4000]);
+        // the failed search, the retry and one past the item as the buffer was full
+        assert_eq!(tree.searches, 3);
+        assert!(args.buf_size() >= size_of::<btrfs_ioctl_search_header>() + tree.items[0].len);
+    }
+
+    #[test]
+    fn fails_on_overflow_beyond_the_max_buffer() {
+        let mut tree = FakeTree {
+            items: vec![inline(1 << 30, 0, SearchArgs::MAX_BUF_SIZE)],
+            ..Default::default()
+        };
+        let mut args = SearchArgs::new_search_file_extent_data(INO, 0, SearchArgs::MIN_BUF_SIZE);
+        let buf_size = args.buf_size();
+        let mut iter = get_file_extents_with(&mut tree, &mut args).unwrap();
+        match (&mut iter).next() {
+            Some(Err(CompvizError::Io(err))) => {
+                assert_eq!(err.raw_os_error(), Some(libc::EOVERFLOW))
+            }
+            other => panic!("expected EOVERFLOW, got {other:?}"),
+        }
+        assert_eq!(tree.searches, 1);
+        assert_eq!(args.buf_size(), buf_size);
+    }
+
+    #[test]
+    fn same_key_type_in_consecutive_batches() {
+        // one item per batch, so the second is only found by resuming after the first
+        let mut tree = FakeTree {
+            items: vec![regular(0), regular(4096)],
+            ..Default::default()
+        };
+        assert_eq!(offsets(&mut tree, SearchArgs::MIN_BUF_SIZE), [0, 4096]);
+        assert_eq!(tree.searches, 3);
+    }
+
+    #[test]
+    fn compressed_inline_disk_num_bytes() {
+        let mut tree = FakeTree {
+            items: vec![inline(3000, 1, 1200)],
+            ..Default::default()
+        };
+        let mut args =
+            SearchArgs::new_search_file_extent_data(INO, 0, SearchArgs::DEFAULT_BUF_SIZE);
+        let mut iter = get_file_extents_with(&mut tree, &mut args).unwrap();
+        let item = (&mut iter).next().unwrap().unwrap();
+        assert_eq!(item.type_(), BtrfsFileExtentType::Inline);
+        assert_eq!(item.disk_bytenr(), None);
+        assert_eq!(item.disk_num_bytes(), 1200);
+        assert_eq!(item.num_bytes(), 3000);
+        assert!(item.disk_num_bytes() < item.ram_bytes());
+        assert!((&mut iter).next().is_none());
+    }
+
+    #[test]
+    fn search_error_by_errno() {
+        let error = |errno| search_error(io::Error::from_raw_os_error(errno));
+        assert!(error(libc::EINTR).is_none());
+        assert!(matches!(error(libc::ENOTTY), Some(CompvizError::NotBtrfs)));
+        assert!(matches!(
+            error(libc::EPERM),
+            Some(CompvizError::PermissionDenied(_))
+        ));
+        assert!(matches!(
+            error(libc::EACCES),
+            Some(CompvizError::PermissionDenied(_))
+        ));
+        assert!(matches!(error(libc::ENOENT), Some(CompvizError::Io(_))));
+        assert!(matches!(error(libc::EOVERFLOW), Some(CompvizError::Io(_))));
     }
 }
@@ -0,0 +1,483 @@
+use std::{
+    env,
+    io::{self, IsTerminal, Write},
+    path::PathBuf,
+    sync::{Arc, Mutex},
+};
+
+use clap::Parser;
+
+use compviz::{
+    format::{
+        ndjson_file_line, per_file_line, ColorChoice, Column, FormatOptions, OutputFormat,
+        SortOrder, SummaryFormat, Units,
+    },
+    AnalyzeOptions, ApproxDedup, CompressionType, DefragThresholds, FileCallback, GroupBy,
+};
+
+/// Where the report goes, shared with the scan threads for `--per-file` and `--format ndjson`.
+pub type Output = Arc<Mutex<dyn Write + Send>>;
+
+/// Visualize btrfs filesystem compression statistics.
+#[derive(Debug, Parser)]
+#[command(version, about)]
+pub struct Cli {
+    /// Files or directories to scan. Extents shared between them are only counted once.
+    ///
+    /// A single `-` reads them from stdin like `--stdin`.
+    #[arg(required_unless_present_any = ["replay", "from_index", "stdin"])]
+    pub paths: Vec<PathBuf>,
+    /// Read the paths to scan from stdin, one per line, e.g. from `fd -e log`.
+    ///
+    /// Only the regular files listed are scanned, directories and other entries are skipped
+    /// rather than recursed into, so the output of `find .` counts every file once.
+    #[arg(long, conflicts_with_all = ["paths", "replay", "from_index"])]
+    pub stdin: bool,
+    /// Report the extents of inode INO of the subvolume the single given path is in,
+    /// e.g. its mount point, instead of scanning it. For inodes without a known path,
+    /// such as ones found with `btrfs inspect-internal`.
+    #[arg(long, value_name = "INO", conflicts_with_all = ["stdin", "replay", "from_index", "snapshot", "defrag_candidates"])]
+    pub inode: Option<u64>,
+    /// Paths read from stdin are separated by NUL instead of newline, as by `find -print0`.
+    #[arg(short = '0', long)]
+    pub null: bool,
+
+    /// Output format.
+    ///
+    /// JSON output carries a `schema_version`, bumped whenever a field is renamed, removed or
+    /// changes its meaning. Fields may be added without a bump.
+    #[arg(long, value_enum, default_value_t)]
+    pub format: OutputFormat,
+    /// Shorthand for `--format json`.
+    #[arg(long, conflicts_with = "format")]
+    pub json: bool,
+    /// Print the paths of `--per-file`, `--top`, `--defrag-candidates` and `--format ndjson`
+    /// relative to the given path they were found under, for reports that compare across
+    /// machines mounting the filesystem elsewhere.
+    #[arg(long)]
+    pub relative: bool,
+    /// End `--format influx` lines with the current time instead of leaving it to the server.
+    #[arg(long)]
+    pub timestamp: bool,
+    /// Pretty-print JSON output over several indented lines, for reading it yourself.
+    #[arg(long)]
+    pub pretty: bool,
+    /// Shorthand for `--format compsize`, output as compsize prints it.
+    #[arg(long, conflicts_with_all = ["format", "json"])]
+    pub compsize_compat: bool,
+    /// How the leading counts line is rendered.
+    #[arg(long, value_enum, default_value_t)]
+    pub summary_format: SummaryFormat,
+    /// Print sizes in SI units (KB, MB, GB) instead of binary ones (KiB, MiB, GiB).
+    #[arg(long)]
+    pub si: bool,
+    /// Print sizes as plain byte counts.
+    #[arg(long, conflicts_with = "si")]
+    pub bytes: bool,
+    /// Columns of the table to show in this order, separated by commas,
+    /// e.g. `type,disk,saved,ratio`. All of them by default.
+    #[arg(long, value_name = "COLUMNS", value_enum, value_delimiter = ',')]
+    pub columns: Vec<Column>,
+    /// Highlight poorly compressed rows of the table red and well compressed ones green.
+    #[arg(long, value_name = "WHEN", value_enum, default_value_t)]
+    pub color: ColorChoice,
+    /// Order of the per-type rows. TOTAL always comes first.
+    #[arg(long, value_enum, default_value_t)]
+    pub sort: SortOrder,
+
+    /// Add a row to the table with the bytes of inline extents alone, which are also part
+    /// of their compression type's row. Shows how much small files take up.
+    #[arg(long)]
+    pub inline_detail: bool,
+    /// Print only the "Processed ..." line with the total bytes saved and ratio, no table.
+    #[arg(long)]
+    pub summary_only: bool,
+
+    /// Print a line per regular file before the table: its dominant compression type,
+    /// disk usage and referenced bytes. Disk usage of extents shared with a file listed
+    /// earlier is attributed to that file only.
+    #[arg(long)]
+    pub per_file: bool,
+
+    /// List every file that couldn't be read, not only the first few.
+    #[arg(long)]
+    pub errors: bool,
+    /// Log the traversal to stderr up to LEVEL, e.g. `debug`, or with `RUST_LOG` style directives.
+    ///
+    /// Overrides `RUST_LOG`. Nothing is logged if neither is set.
+    #[arg(long, value_name = "LEVEL")]
+    pub log_level: Option<String>,
+    /// Show a progress bar on stderr. The files are counted first to know the total,
+    /// which takes a walk of the whole tree before the scan even starts.
+    #[arg(long)]
+    pub progress: bool,
+    /// Print how long the scan took and how many files per second it got through.
+    #[arg(long)]
+    pub timing: bool,
+    /// Print the thread count, buffer size and dedup mode the scan runs with, list the skipped
+    /// non-regular files, i.e. sockets, FIFOs and device nodes,
+    /// and report the memory taken by deduplicating extents.
+    #[arg(short, long)]
+    pub verbose: bool,
+
+    /// Number of threads to scan with.
+    ///
+    /// Takes precedence over the RAYON_NUM_THREADS env var. If neither is set, the count
+    /// depends on the logical CPUs `n`: `n` up to 6, `n/2+1` below 24, and 24 otherwise.
+    #[arg(short = 'j', long, value_name = "N", value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..))]
+    pub threads: Option<usize>,
+    /// List the N regular files using the most disk before the table, largest first.
+    /// Disk usage of extents shared between files is attributed to whichever was scanned first.
+    ///
+    /// With `--by-extension` or `--by-dir`, only the N groups using the most disk are listed instead.
+    #[arg(long, value_name = "N")]
+    pub top: Option<usize>,
+    /// Add a table of the statistic per file extension, sorted by disk usage.
+    #[arg(long)]
+    pub by_extension: bool,
+    /// Add a table of the statistic per directory directly below the given paths,
+    /// sorted by disk usage.
+    #[arg(long, conflicts_with_all = ["by_extension", "from_index"])]
+    pub by_dir: bool,
+
+    /// Scan even if a path is not on btrfs, e.g. for overlays on top of btrfs.
+    #[arg(long)]
+    pub force: bool,
+    /// Don't descend into other filesystems mounted below the given paths.
+    ///
+    /// Note that every btrfs subvolume has its own device number,
+    /// so nested subvolumes are skipped as well.
+    #[arg(short = 'x', long)]
+    pub one_file_system: bool,
+    /// Only count files on the filesystem mounted at MOUNT, can be repeated.
+    ///
+    /// Directories on the filesystem of the given paths are still descended into to reach
+    /// the mounts below them, but their files are skipped, as are all other mounts.
+    /// Like with `-x`, nested subvolumes of a mount have their own device number and must
+    /// be listed too. If the given path isn't on btrfs itself, e.g. `/`, pass `--force`.
+    #[arg(long, value_name = "MOUNT", conflicts_with = "one_file_system")]
+    pub follow_mounts: Vec<PathBuf>,
+    /// Skip entries matching GLOB, excluded directories aren't descended into. Can be repeated.
+    ///
+    /// A pattern containing `/` is matched against the whole path, as it was reached from
+    /// the given paths. Other patterns are matched against the file name only.
+    #[arg(long, value_name = "GLOB", value_parser = glob::Pattern::new)]
+    pub exclude: Vec<glob::Pattern>,
+    /// Only count extents written in generation N or later, e.g. since the last `btrfs send`.
+    #[arg(long, value_name = "N")]
+    pub since_generation: Option<u64>,
+    /// Only count extents compressed with TYPE, e.g. `zlib`. Can be repeated.
+    ///
+    /// TOTAL then covers the selected types only, and `--per-file` only lists files with such extents.
+    #[arg(long = "type", value_name = "TYPE", value_parser = |s: &str| CompressionType::try_from(s.to_string()))]
+    pub types: Vec<CompressionType>,
+    /// Skip regular files smaller than SIZE bytes, e.g. `512K` or `10M`.
+    ///
+    /// `K`, `M`, `G` and `T` suffixes are binary, as are `KiB`, `MiB`..., while `KB`, `MB`... are SI.
+    #[arg(long, value_name = "SIZE", value_parser = parse_size)]
+    pub min_size: Option<u64>,
+    /// Skip regular files larger than SIZE bytes, accepting the same suffixes as `--min-size`.
+    #[arg(long, value_name = "SIZE", value_parser = parse_size)]
+    pub max_size: Option<u64>,
+    /// Count the disk usage of every reference to an extent instead of only the first one.
+    ///
+    /// Per-file numbers then add up like `du` regardless of reflinks, but totals exceed
+    /// the physical usage wherever reflinked files or snapshots share extents.
+    #[arg(long)]
+    pub no_dedup: bool,
+    /// Deduplicate extents with a bloom filter of SIZE, e.g. 512M, instead of exactly.
+    ///
+    /// Bounds the memory taken on filesystems with hundreds of millions of extents. At a false
+    /// positive rate of 1% it holds about one extent per 1.2 bytes, each false positive
+    /// missing an extent in the counts. Sharing isn't reported then.
+    #[arg(long, value_name = "SIZE", value_parser = parse_size, conflicts_with = "no_dedup")]
+    pub approx_dedup: Option<u64>,
+    /// False positive rate the --approx-dedup filter is tuned for.
+    #[arg(long, value_name = "RATE", default_value_t = 0.01, requires = "approx_dedup", value_parser = parse_rate)]
+    pub approx_dedup_fp_rate: f64,
+    /// Report the disk usage saved by extents shared between distinct files, e.g. by
+    /// `cp --reflink`, at least their size once. Hardlinks are a single file.
+    #[arg(long)]
+    pub reflinks: bool,
+    /// Leave preallocated extents out of the byte counts.
+    ///
+    /// They're counted as uncompressed by default, as they take disk space like compsize
+    /// and `df` report it, although they hold no data yet.
+    #[arg(long)]
+    pub no_prealloc: bool,
+    /// Bucket extents by their disk size in powers of two, and estimate the median size from that.
+    #[arg(long)]
+    pub extent_sizes: bool,
+    /// Count files by their number of extents, highly fragmented ones may be worth a defrag.
+    #[arg(long)]
+    pub fragmentation: bool,
+    /// Instead of the report, list the files that are both fragmented and poorly compressed,
+    /// the best candidates for `btrfs filesystem defragment -czstd`, most extents first.
+    ///
+    /// Each is printed as `PATH<TAB>EXTENTS<TAB>RATIO` to be piped into a script.
+    #[arg(long, conflicts_with_all = ["replay", "from_index"])]
+    pub defrag_candidates: bool,
+    /// Files listed by --defrag-candidates need more than N extents.
+    #[arg(
+        long,
+        value_name = "N",
+        default_value_t = 50,
+        requires = "defrag_candidates"
+    )]
+    pub defrag_min_extents: usize,
+    /// Files listed by --defrag-candidates need a compression ratio below RATIO.
+    #[arg(
+        long,
+        value_name = "RATIO",
+        default_value_t = 1.2,
+        requires = "defrag_candidates"
+    )]
+    pub defrag_max_ratio: f64,
+    /// Bucket files by their compression ratio, counting files and disk usage per bucket.
+    #[arg(long)]
+    pub histogram: bool,
+    /// Descend at most N levels below the given paths. 0 scans only the given paths
+    /// themselves, 1 also their immediate children, and so on.
+    #[arg(long, value_name = "N")]
+    pub max_depth: Option<usize>,
+    /// Follow symlinks found while descending, instead of skipping them.
+    ///
+    /// The given paths are always followed if they are symlinks, with or without this.
+    /// A directory reached through several links, or a link back to a parent, is only scanned once.
+    /// With `-x`, a followed link is checked by the device of its target.
+    #[arg(short = 'L', long)]
+    pub follow_symlinks: bool,
+    /// A snapshot of the given paths to scan along with them, can be repeated.
+    ///
+    /// Disk usage is then also split by whether it's referenced only from the given paths,
+    /// from both, or only from the snapshots, which is what deleting those would free.
+    #[arg(long, value_name = "PATH")]
+    pub snapshot: Vec<PathBuf>,
+    /// Bucket disk usage by how many generations ago it was written.
+    #[arg(long)]
+    pub extent_generation_histogram: bool,
+    /// Size of the buffer each thread fetches extents into, 64KiB by default.
+    ///
+    /// A smaller buffer saves memory with many threads,
+    /// a larger one takes fewer round-trips for files with many extents.
+    #[arg(long, value_name = "BYTES")]
+    pub buffer_size: Option<usize>,
+
+    /// Write the report to FILE instead of stdout, errors still go to stderr.
+    #[arg(short, long, value_name = "FILE")]
+    pub output: Option<PathBuf>,
+    /// Write the statistic to FILE as JSON, to compare later runs against with `--diff`.
+    #[arg(long, value_name = "FILE")]
+    pub save: Option<PathBuf>,
+    /// Compare the result against a statistic written by `--save`, printing the changes
+    /// after the table.
+    #[arg(long, value_name = "FILE")]
+    pub diff: Option<PathBuf>,
+    /// Write the per-file statistics to FILE as a binary index.
+    #[arg(long, value_name = "FILE")]
+    pub dump_index: Option<PathBuf>,
+    /// Render an index written by `--dump-index` instead of scanning.
+    #[arg(long, value_name = "FILE", conflicts_with_all = ["paths", "replay"])]
+    pub from_index: Option<PathBuf>,
+    /// Render a previously dumped JSON statistic instead of scanning.
+    #[arg(long, value_name = "FILE", hide = true, conflicts_with = "paths")]
+    pub replay: Option<PathBuf>,
+
+    /// Rescan every SECS seconds and redraw the output, until interrupted with Ctrl-C.
+    ///
+    /// The screen is cleared before every report only when printing to a terminal.
+    #[arg(long, value_name = "SECS", conflicts_with_all = ["replay", "from_index"])]
+    pub watch: Option<u64>,
+
+    /// POST the result as JSON to URL after the scan.
+    #[arg(long, value_name = "URL")]
+    pub webhook: Option<String>,
+    /// Extra header for the webhook request, as `Name: value`. Can be repeated.
+    #[arg(long, value_name = "HEADER", requires = "webhook")]
+    pub webhook_header: Vec<String>,
+    /// Fail the run if the webhook can't be delivered, instead of only warning.
+    #[arg(long, requires = "webhook")]
+    pub webhook_required: bool,
+}
+impl Cli {
+    /// Whether the paths to scan are to be read from stdin.
+    pub fn paths_from_stdin(&self) -> bool {
+        self.stdin || self.paths.len() == 1 && self.paths[0].as_os_str() == "-"
+    }
+    pub fn output_format(&self) -> OutputFormat {
+        if self.json {
+            OutputFormat::Json
+        } else if self.compsize_compat {
+            OutputFormat::Compsize
+        } else {
+            self.format
+        }
+    }
+    /// `out` is where `--per-file` and `--format ndjson` lines are written to.
+    pub fn analyze_options(&self, out: Output) -> AnalyzeOptions {
+        AnalyzeOptions {
+            threads: self.threads,
+            count_only: false,
+            progress: None,
+            buf_size: self.buffer_size,
+            force: self.force,
+            generation_histogram: self.extent_generation_histogram,
+            dump_index: self.dump_index.is_some(),
+            one_file_system: self.one_file_system,
+            follow_mounts: self.follow_mounts.clone(),
+            relative: self.relative,
+            // with groups, --top limits those instead
+            top: self.top.filter(|_| self.group_by().is_none()),
+            group_by: self.group_by(),
+            ratio_histogram: self.histogram,
+            extent_size_histogram: self.extent_sizes,
+            fragmentation: self.fragmentation,
+            defrag_candidates: self.defrag_candidates.then_some(DefragThresholds {
+                min_extents: self.defrag_min_extents,
+                max_ratio: self.defrag_max_ratio,
+            }),
+            no_dedup: self.no_dedup,
+            no_prealloc: self.no_prealloc,
+            approx_dedup: self.approx_dedup.map(|memory| ApproxDedup {
+                memory: memory as usize,
+                fp_rate: self.approx_dedup_fp_rate,
+            }),
+            list_skipped: self.verbose,
+            max_depth: self.max_depth,
+            follow_symlinks: self.follow_symlinks,
+            snapshots: self.snapshot.clone(),
+            exclude: self.exclude.clone(),
+            since_generation: self.since_generation,
+            types: self.types.clone(),
+            min_size: self.min_size,
+            max_size: self.max_size,
+            on_file: if self.output_format() == OutputFormat::Ndjson {
+                Some(FileCallback::new(move |path, stat| {
+                    let _ = ndjson_file_line(&mut *out.lock().unwrap(), path, stat);
+                }))
+            } else if self.per_file {
+                let opts = self.format_options();
+                Some(FileCallback::new(move |path, stat| {
+                    // like println!, but there's no way to fail the scan from here
+                    let _ = writeln!(out.lock().unwrap(), "{}", per_file_line(path, stat, &opts));
+                }))
+            } else {
+                None
+            },
+        }
+    }
+    pub fn group_by(&self) -> Option<GroupBy> {
+        if self.by_extension {
+            Some(GroupBy::Extension)
+        } else if self.by_dir {
+            Some(GroupBy::TopDir)
+        } else {
+            None
+        }
+    }
+    pub fn format_options(&self) -> FormatOptions {
+        FormatOptions {
+            summary: self.summary_format,
+            sort: self.sort,
+            inline_detail: self.inline_detail,
+            summary_only: self.summary_only,
+            columns: self.columns.clone(),
+            reflinks: self.reflinks,
+            pretty: self.pretty,
+            timestamp: self.timestamp,
+            color: self.output_format() == OutputFormat::Human
+                && match self.color {
+                    ColorChoice::Always => true,
+                    ColorChoice::Never => false,
+                    ColorChoice::Auto => {
+                        self.output.is_none()
+                            && io::stdout().is_terminal()
+                            && env::var_os("NO_COLOR").is_none_or(|v| v.is_empty())
+                    }
+                },
+            units: if self.si {
+                Units::Decimal
+            } else if self.bytes {
+                Units::Bytes
+            } else {
+                Units::Binary
+            },
+        }
+    }
+}
+
+/// Parse a rate strictly between 0 and 1, e.g. `0.01`.
+fn parse_rate(s: &str) -> Result<f64, String> {
+    match s.parse::<f64>() {
+        Ok(rate) if rate > 0.0 && rate < 1.0 => Ok(rate),
+        _ => Err(format!(
+            "invalid rate {s:?}, expected a number between 0 and 1"
+        )),
+    }
+}
+
+/// Parse a byte count with an optional unit suffix, e.g. `4096`, `10M`, `1.5GiB` or `2GB`.
+fn parse_size(s: &str) -> Result<u64, String> {
+    let s = s.trim();
+    let split = s
+        .find(|c: char| !c.is_ascii_digit() && c != '.')
+        .unwrap_or(s.len());
+    let (number, suffix) = s.split_at(split);
+    let number: f64 = number
+        .parse()
+        .map_err(|_| format!("invalid size {s:?}, expected e.g. 4096 or 10M"))?;
+    let (prefix, base) = match suffix.trim_start() {
+        "" | "B" => ("", 1u64),
+        suffix => match suffix.strip_suffix("iB") {
+            Some(prefix) => (prefix, 1024),
+            None => match suffix.strip_suffix('B') {
+                Some(prefix) => (prefix, 1000),
+                None => (suffix, 1024),
+            },
+        },
+    };
+    let exp = match prefix.to_ascii_uppercase().as_str() {
+        "" => 0,
+        "K" => 1,
+        "M" => 2,
+        "G" => 3,
+        "T" => 4,
+        _ => {
+            return Err(format!(
+                "unknown size suffix {suffix:?}, expected one of K, M, G, T"
+            ))
+        }
+    };
+    // fractions of a unit such as 1.1M rarely come out whole, so round to the nearest byte
+    let bytes = (number * base.pow(exp) as f64).round();
+    if bytes >= u64::MAX as f64 {
+        return Err(format!("size {s:?} is too large"));
+    }
+    Ok(bytes as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_size_valid() {
+        assert_eq!(parse_size("4096"), Ok(4096));
+        assert_eq!(parse_size("4096B"), Ok(4096));
+        assert_eq!(parse_size("10M"), Ok(10 << 20));
+        assert_eq!(parse_size("10k"), Ok(10 << 10));
+        assert_eq!(parse_size("1.5GiB"), Ok(3 << 29));
+        assert_eq!(parse_size("2GB"), Ok(2_000_000_000));
+        assert_eq!(parse_size(" 10 M "), Ok(10 << 20));
+        assert_eq!(parse_size("1.1M"), Ok(1_153_434));
+        assert_eq!(parse_size("2.3G"), Ok(2_469_606_195));
+        assert_eq!(parse_size("1.5"), Ok(2));
+    }
+
+    #[test]
+    fn parse_size_invalid() {
+        for s in ["", "M", "-1", "-1.5M", "10X", "99999999999T"] {
+            assert!(parse_size(s).is_err(), "{s:?} parsed");
+        }
+    }
+}
@@ -0,0 +1,27 @@
+use std::io;
+
+/// Failures of the btrfs queries callers may want to tell apart.
+///
+/// The scan wraps these into its [anyhow::Error]s, so they can be told apart there with
+/// `downcast_ref`, see [crate::ScanError::compviz_error].
+#[derive(Debug, thiserror::Error)]
+pub enum CompvizError {
+    /// The tree search ioctl isn't supported by the filesystem of the file,
+    /// or `statfs(2)` didn't report btrfs.
+    #[error("not on a btrfs filesystem")]
+    NotBtrfs,
+    /// The tree search ioctl requires `CAP_SYS_ADMIN`.
+    #[error(transparent)]
+    PermissionDenied(io::Error),
+    /// Any other IO error, e.g. of a file gone in the meantime.
+    #[error(transparent)]
+    Io(io::Error),
+}
+impl From<io::Error> for CompvizError {
+    fn from(err: io::Error) -> Self {
+        match err.kind() {
+            io::ErrorKind::PermissionDenied => CompvizError::PermissionDenied(err),
+            _ => CompvizError::Io(err),
+        }
+    }
+}
@@ -0,0 +1,1169 @@
+use std::{
+    borrow::Cow,
+    cmp::Reverse,
+    collections::{BTreeMap, BTreeSet, HashMap},
+    io::{self, Write},
+    path::Path,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use clap::ValueEnum;
+use humansize::{FormatSize, BINARY, DECIMAL};
+use owo_colors::OwoColorize;
+use serde::Serialize;
+
+use crate::{
+    CompressionType, ExtentInfo, Statistic, EXTENT_SIZE_BUCKETS, FRAGMENTATION_BUCKETS,
+    FRAGMENTATION_BUCKET_BOUNDS, GENERATION_AGE_BUCKETS, MIN_BUCKETED_EXTENT_SIZE, RATIO_BUCKETS,
+    RATIO_BUCKET_BOUNDS,
+};
+
+/// Renders a [Statistic] into some output format.
+///
+/// Implementations only read the statistic, so the same result can be rendered
+/// by several formatters without rescanning.
+pub trait Formatter {
+    fn format(&self, stat: &Statistic, writer: &mut dyn Write) -> io::Result<()>;
+}
+
+/// All output formats compviz knows about, selected by `--format`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum OutputFormat {
+    /// The compsize-like fixed width table.
+    #[default]
+    Human,
+    /// The full statistic as a single JSON object, see [JSON_SCHEMA_VERSION].
+    Json,
+    /// The table as CSV with raw byte counts, for spreadsheets.
+    Csv,
+    /// Metrics in the Prometheus text format, e.g. for node_exporter's textfile collector.
+    Prometheus,
+    /// One JSON object per file as it's scanned, then one with the whole statistic.
+    Ndjson,
+    /// The table as Markdown, e.g. for pasting into issues.
+    Markdown,
+    /// The same text compsize prints, for scripts parsing that.
+    Compsize,
+    /// The same fields as JSON, as a YAML document.
+    Yaml,
+    /// InfluxDB line protocol, one line per compression type and one of the totals.
+    Influx,
+}
+impl OutputFormat {
+    pub fn formatter(&self, opts: FormatOptions) -> Box<dyn Formatter> {
+        match self {
+            OutputFormat::Human => Box::new(HumanFormatter { opts }),
+            OutputFormat::Json => Box::new(JsonFormatter { opts }),
+            OutputFormat::Csv => Box::new(CsvFormatter { opts }),
+            OutputFormat::Prometheus => Box::new(PrometheusFormatter),
+            OutputFormat::Ndjson => Box::new(NdjsonFormatter),
+            OutputFormat::Markdown => Box::new(MarkdownFormatter { opts }),
+            OutputFormat::Compsize => Box::new(CompsizeFormatter { opts }),
+            OutputFormat::Yaml => Box::new(YamlFormatter),
+            OutputFormat::Influx => Box::new(InfluxFormatter { opts }),
+        }
+    }
+}
+
+/// How the leading "Processed ..." line is rendered, selected by `--summary-format`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum SummaryFormat {
+    #[default]
+    Prose,
+    /// `key=value` pairs, for quick scripting without switching to a structured format.
+    Machine,
+}
+
+/// Order of the per-type rows, selected by `--sort`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum SortOrder {
+    /// By compression type: none, zlib, lzo, zstd, unknown.
+    #[default]
+    Type,
+    /// By disk usage, largest first.
+    Disk,
+}
+
+/// When to color the output, selected by `--color`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum ColorChoice {
+    /// If printing the table to a terminal and `NO_COLOR` isn't set.
+    #[default]
+    Auto,
+    Always,
+    Never,
+}
+
+/// A column of the human table, selected by `--columns`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Column {
+    /// The compression type, or TOTAL.
+    Type,
+    /// Disk usage in percent of the uncompressed size.
+    Percent,
+    /// Uncompressed size over disk usage.
+    Ratio,
+    Disk,
+    Uncompressed,
+    Referenced,
+    /// Bytes saved by compression.
+    Saved,
+    /// Number of regular extents.
+    #[value(alias = "n_extents")]
+    Extents,
+}
+impl Column {
+    /// All of them in the order of the default table.
+    pub const DEFAULT: [Column; 8] = [
+        Column::Type,
+        Column::Percent,
+        Column::Ratio,
+        Column::Disk,
+        Column::Uncompressed,
+        Column::Referenced,
+        Column::Saved,
+        Column::Extents,
+    ];
+    fn header(self) -> &'static str {
+        match self {
+            Column::Type => "Type",
+            Column::Percent => "Perc",
+            Column::Ratio => "Ratio",
+            Column::Disk => "Disk Usage",
+            Column::Uncompressed => "Uncompressed",
+            Column::Referenced => "Referenced",
+            Column::Saved => "Saved",
+            Column::Extents => "Extents",
+        }
+    }
+    fn width(self) -> usize {
+        match self {
+            Column::Type | Column::Extents => 10,
+            Column::Percent | Column::Ratio => 8,
+            _ => 12,
+        }
+    }
+}
+
+/// Units human readable sizes are printed in.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Units {
+    /// KiB, MiB, GiB...
+    #[default]
+    Binary,
+    /// SI units: KB, MB, GB...
+    Decimal,
+    /// Plain integer byte counts, for parsing.
+    Bytes,
+}
+
+/// Knobs shared by the formatters, each formatter ignores what doesn't apply to it.
+#[derive(Debug, Clone, Default)]
+pub struct FormatOptions {
+    pub summary: SummaryFormat,
+    pub sort: SortOrder,
+    pub units: Units,
+    /// Add a row with the bytes of inline extents alone.
+    pub inline_detail: bool,
+    /// Only the summary line, nothing after it.
+    pub summary_only: bool,
+    /// End the lines of [OutputFormat::Influx] with the current time,
+    /// instead of leaving it to the server receiving them.
+    pub timestamp: bool,
+    /// Pretty-print [OutputFormat::Json] output instead of writing a single line.
+    pub pretty: bool,
+    /// Report the disk usage saved by extents shared between files, see
+    /// [Statistic::n_shared_extents].
+    pub reflinks: bool,
+    /// Columns of the human table in this order, [Column::DEFAULT] if empty.
+    pub columns: Vec<Column>,
+    /// Highlight the rows of the table by their ratio with ANSI colors,
+    /// see [POOR_RATIO] and [GOOD_RATIO].
+    pub color: bool,
+}
+impl FormatOptions {
+    fn columns(&self) -> &[Column] {
+        if self.columns.is_empty() {
+            &Column::DEFAULT
+        } else {
+            &self.columns
+        }
+    }
+    /// Format a byte count in the selected [Units].
+    pub fn size(&self, bytes: usize) -> String {
+        match self.units {
+            Units::Binary => bytes.format_size(BINARY),
+            Units::Decimal => bytes.format_size(DECIMAL),
+            Units::Bytes => bytes.to_string(),
+        }
+    }
+}
+
+/// Hints derived from `stat` that may be worth acting on, one sentence each,
+/// printed after the table of [HumanFormatter].
+pub fn advisories(stat: &Statistic) -> Vec<String> {
+    let mut advisories = Vec::new();
+    for (compression, info) in &stat.extent_info {
+        // files only reference part of the extents, or compression made them larger
+        if info.disk_bytes > info.referenced_bytes {
+            advisories.push(format!(
+                "{compression} is inflating: disk > referenced, consider recompressing."
+            ));
+        }
+    }
+    advisories
+}
+
+/// One line summarizing the statistic of a single file for `--per-file` and `--top`:
+/// its dominant compression type, ratio, disk usage and referenced bytes, then the path.
+pub fn per_file_line(path: &Path, stat: &Statistic, opts: &FormatOptions) -> String {
+    let total = stat.total();
+    format!(
+        "{:<10} {:<8} {:<12} {:<12} {}",
+        stat.dominant_compression()
+            .map_or_else(|| "-".to_string(), |c| c.to_string()),
+        format!("{:.2}x", total.ratio()),
+        opts.size(total.disk_bytes),
+        opts.size(total.referenced_bytes),
+        path.display()
+    )
+}
+
+#[derive(Serialize)]
+struct FileRecord<'a> {
+    path: Cow<'a, str>,
+    disk_bytes: usize,
+    uncompressed_bytes: usize,
+    referenced_bytes: usize,
+    compression: &'a BTreeMap<CompressionType, ExtentInfo>,
+}
+
+/// Write the `--format ndjson` line of a single file: its totals and per-type breakdown.
+///
+/// It's a single line, so files scanned in parallel don't interleave as long as
+/// `f` is locked around the call.
+pub fn ndjson_file_line(f: &mut dyn Write, path: &Path, stat: &Statistic) -> io::Result<()> {
+    let total = stat.total();
+    let record = FileRecord {
+        path: path.to_string_lossy(),
+        disk_bytes: total.disk_bytes,
+        uncompressed_bytes: total.uncompressed_bytes,
+        referenced_bytes: total.referenced_bytes,
+        compression: &stat.extent_info,
+    };
+    serde_json::to_writer(&mut *f, &record)?;
+    writeln!(f)
+}
+
+/// A table of `groups` by disk usage, largest first, with each group's share of the files
+/// and disk usage in `total`. Only the first `limit` groups are listed if given.
+pub fn group_table(
+    f: &mut dyn Write,
+    title: &str,
+    groups: &HashMap<String, Statistic>,
+    total: &Statistic,
+    limit: Option<usize>,
+    opts: &FormatOptions,
+) -> io::Result<()> {
+    let total_disk = total.total().disk_bytes;
+    let share = |part: usize, whole: usize| {
+        if whole == 0 {
+            0.0
+        } else {
+            part as f64 / whole as f64 * 100.0
+        }
+    };
+    let mut rows: Vec<_> = groups
+        .iter()
+        .map(|(key, stat)| (key, stat, stat.total()))
+        .collect();
+    rows.sort_by_key(|(key, _, info)| (Reverse(info.disk_bytes), *key));
+    writeln!(
+        f,
+        "{:<20} {:<10} {:<8} {:<12} {:<8} {:<12} {:<8}",
+        title, "Files", "Files %", "Disk Usage", "Disk %", "Uncompressed", "Ratio"
+    )?;
+    for (key, stat, info) in rows.into_iter().take(limit.unwrap_or(usize::MAX)) {
+        writeln!(
+            f,
+            "{:<20} {:<10} {:<8} {:<12} {:<8} {:<12} {:<8}",
+            key,
+            stat.n_files,
+            format!("{:.2}%", share(stat.n_files, total.n_files)),
+            opts.size(info.disk_bytes),
+            format!("{:.2}%", share(info.disk_bytes, total_disk)),
+            opts.size(info.uncompressed_bytes),
+            format!("{:.2}x", info.ratio())
+        )?;
+    }
+    Ok(())
+}
+
+/// A table of how `stat` changed since `baseline`, per compression type present in either.
+pub fn diff_table(
+    f: &mut dyn Write,
+    baseline: &Statistic,
+    stat: &Statistic,
+    opts: &FormatOptions,
+) -> io::Result<()> {
+    let delta = |old: usize, new: usize| signed_delta(old, new, |n| opts.size(n));
+    let count = |old: usize, new: usize| signed_delta(old, new, |n| n.to_string());
+    writeln!(
+        f,
+        "Files {} ({}), extents {} ({}).",
+        stat.n_files,
+        count(baseline.n_files, stat.n_files),
+        stat.n_extents,
+        count(baseline.n_extents, stat.n_extents)
+    )?;
+    writeln!(
+        f,
+        "{:<10} {:<16} {:<16} {:<16}",
+        "Type", "Disk Usage", "Uncompressed", "Referenced"
+    )?;
+    let empty = ExtentInfo::default();
+    let types: BTreeSet<_> = baseline
+        .extent_info
+        .keys()
+        .chain(stat.extent_info.keys())
+        .collect();
+    let rows = [("TOTAL".to_string(), baseline.total(), stat.total())]
+        .into_iter()
+        .chain(types.into_iter().map(|c| {
+            (
+                c.to_string(),
+                baseline.extent_info.get(c).unwrap_or(&empty).clone(),
+                stat.extent_info.get(c).unwrap_or(&empty).clone(),
+            )
+        }));
+    for (name, old, new) in rows {
+        writeln!(
+            f,
+            "{:<10} {:<16} {:<16} {:<16}",
+            name,
+            delta(old.disk_bytes, new.disk_bytes),
+            delta(old.uncompressed_bytes, new.uncompressed_bytes),
+            delta(old.referenced_bytes, new.referenced_bytes)
+        )?;
+    }
+    Ok(())
+}
+
+/// `new - old` with an explicit sign, the magnitude rendered by `fmt`.
+fn signed_delta(old: usize, new: usize, fmt: impl Fn(usize) -> String) -> String {
+    if new >= old {
+        format!("+{}", fmt(new - old))
+    } else {
+        format!("-{}", fmt(old - new))
+    }
+}
+
+/// The per-type rows of `stat` in the given order, ties keep the compression type order.
+pub fn sorted_rows(stat: &Statistic, sort: SortOrder) -> Vec<(CompressionType, &ExtentInfo)> {
+    let mut rows: Vec<_> = stat.extent_info.iter().map(|(c, i)| (*c, i)).collect();
+    if sort == SortOrder::Disk {
+        rows.sort_by_key(|(_, info)| Reverse(info.disk_bytes));
+    }
+    rows
+}
+
+/// Rows of [HumanFormatter] with a ratio below this are red with [FormatOptions::color].
+pub const POOR_RATIO: f64 = 1.1;
+/// Rows of [HumanFormatter] with a ratio above this are green with [FormatOptions::color].
+pub const GOOD_RATIO: f64 = 2.0;
+
+/// The compsize-like fixed width table.
+#[derive(Debug, Default)]
+pub struct HumanFormatter {
+    pub opts: FormatOptions,
+}
+impl HumanFormatter {
+    fn table_header(&self) -> String {
+        self.table_line(|column| column.header().to_string())
+    }
+    /// The row of `info` called `name`, `extents` is shown as the [Column::Extents].
+    fn table_row(&self, name: &str, info: &ExtentInfo, extents: usize) -> String {
+        self.table_line(|column| match column {
+            Column::Type => name.to_string(),
+            Column::Percent => format!("{:.2}%", info.compression_percent()),
+            Column::Ratio => format!("{:.2}x", info.ratio()),
+            Column::Disk => self.opts.size(info.disk_bytes),
+            Column::Uncompressed => self.opts.size(info.uncompressed_bytes),
+            Column::Referenced => self.opts.size(info.referenced_bytes),
+            Column::Saved => self.opts.size(info.saved()),
+            Column::Extents => extents.to_string(),
+        })
+    }
+    /// The cells of the selected columns padded to their widths.
+    fn table_line(&self, cell: impl Fn(Column) -> String) -> String {
+        self.opts
+            .columns()
+            .iter()
+            .map(|&column| format!("{:<width$}", cell(column), width = column.width()))
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+    /// Color the padded `line` of a row by its `ratio`, if colors are enabled at all.
+    fn paint(&self, line: String, ratio: f64) -> String {
+        // without anything on disk there's no ratio to judge
+        if !self.opts.color || ratio == 0.0 {
+            line
+        } else if ratio < POOR_RATIO {
+            line.red().to_string()
+        } else if ratio > GOOD_RATIO {
+            line.green().to_string()
+        } else {
+            line
+        }
+    }
+}
+impl Formatter for HumanFormatter {
+    fn format(&self, stat: &Statistic, f: &mut dyn Write) -> io::Result<()> {
+        let total = stat.total();
+        match self.opts.summary {
+            SummaryFormat::Prose => {
+                write!(
+                    f,
+                    "Processed {} files, {} regular extents ({} refs), {} inline",
+                    stat.n_files, stat.n_extents, stat.n_refs, stat.n_inline
+                )?;
+                // Without anything on disk there's no ratio worth reporting.
+                if total.disk_bytes > 0 {
+                    write!(
+                        f,
+                        ", saved {} ({:.2}x overall)",
+                        self.opts.size(total.saved()),
+                        total.ratio()
+                    )?;
+                }
+                writeln!(f, ".")?;
+            }
+            SummaryFormat::Machine => writeln!(
+                f,
+                "files={} extents={} refs={} inline={} saved={} ratio={:.2}",
+                stat.n_files,
+                stat.n_extents,
+                stat.n_refs,
+                stat.n_inline,
+                total.saved(),
+                total.ratio()
+            )?,
+        }
+        if self.opts.summary_only {
+            return Ok(());
+        }
+        if let Some(since) = stat.since_generation {
+            writeln!(f, "Only counting extents written since generation {since}.")?;
+        }
+        if let Some(rate) = stat.approx_dedup_fp_rate {
+            writeln!(
+                f,
+                "Extents were deduplicated approximately, about {:.2}% of them may be missed.",
+                rate * 100.0
+            )?;
+        }
+        // sharing isn't known with approximate dedup
+        if self.opts.reflinks && stat.approx_dedup_fp_rate.is_none() {
+            // every shared extent would be stored at least once more without reflinks
+            writeln!(
+                f,
+                "Reflink savings: {} across {} extents.",
+                self.opts.size(stat.shared_bytes),
+                stat.n_shared_extents
+            )?;
+        }
+        if stat.n_special > 0 {
+            writeln!(f, "Skipped {} non-regular files.", stat.n_special)?;
+        }
+        if stat.n_vanished > 0 {
+            writeln!(f, "{} files vanished during the scan.", stat.n_vanished)?;
+        }
+        if stat.n_encrypted > 0 {
+            writeln!(
+                f,
+                "{} extent references are encrypted ({}), their sizes may be off.",
+                stat.n_encrypted,
+                self.opts.size(stat.encrypted_bytes)
+            )?;
+        }
+        // with --no-prealloc they're counted but have no bytes
+        if stat.n_prealloc > 0 && stat.prealloc.referenced_bytes == 0 {
+            writeln!(f, "Skipped {} preallocated extents.", stat.n_prealloc)?;
+        }
+        if stat.n_holes > 0 {
+            writeln!(
+                f,
+                "Skipped {} holes ({}).",
+                stat.n_holes,
+                self.opts.size(stat.hole_bytes)
+            )?;
+        }
+        writeln!(f, "{}", self.table_header())?;
+        writeln!(
+            f,
+            "{}",
+            self.paint(
+                self.table_row("TOTAL", &total, total.n_extents),
+                total.ratio()
+            )
+        )?;
+        for (compression, info) in sorted_rows(stat, self.opts.sort) {
+            let row = self.table_row(&compression.to_string(), info, info.n_extents);
+            writeln!(f, "{}", self.paint(row, info.ratio()))?;
+        }
+        if self.opts.inline_detail {
+            writeln!(
+                f,
+                "{}",
+                self.table_row("(inline)", &stat.inline, stat.n_inline)
+            )?;
+        }
+        if stat.prealloc.referenced_bytes > 0 {
+            let prealloc = &stat.prealloc;
+            writeln!(
+                f,
+                "{}",
+                self.table_row("(prealloc)", prealloc, prealloc.n_extents)
+            )?;
+        }
+        let advisories = advisories(stat);
+        if !advisories.is_empty() {
+            writeln!(f)?;
+            writeln!(f, "Advisories")?;
+            for advisory in advisories {
+                writeln!(f, "  {advisory}")?;
+            }
+        }
+        if stat.exclusive_bytes + stat.shared_bytes > 0 {
+            writeln!(f)?;
+            writeln!(f, "{:<20} {:<12}", "Sharing", "Disk Usage")?;
+            writeln!(
+                f,
+                "{:<20} {:<12}",
+                "exclusive",
+                self.opts.size(stat.exclusive_bytes)
+            )?;
+            writeln!(
+                f,
+                "{:<20} {:<12}",
+                "shared",
+                self.opts.size(stat.shared_bytes)
+            )?;
+        }
+        if stat.current_only_bytes + stat.snapshot_shared_bytes + stat.snapshot_only_bytes > 0 {
+            writeln!(f)?;
+            writeln!(f, "{:<20} {:<12}", "Snapshots", "Disk Usage")?;
+            for (name, bytes) in [
+                ("current only", stat.current_only_bytes),
+                ("shared", stat.snapshot_shared_bytes),
+                ("snapshots only", stat.snapshot_only_bytes),
+            ] {
+                writeln!(f, "{:<20} {:<12}", name, self.opts.size(bytes))?;
+            }
+        }
+        if stat.generation_age.iter().any(|&b| b > 0) {
+            writeln!(f)?;
+            writeln!(f, "{:<20} {:<12}", "Generation age", "Disk Usage")?;
+            for (i, bytes) in stat.generation_age.iter().enumerate() {
+                let range = if i + 1 == GENERATION_AGE_BUCKETS {
+                    format!(">= {}", 10u64.pow(i as u32))
+                } else if i == 0 {
+                    "< 10".to_string()
+                } else {
+                    format!("{} - {}", 10u64.pow(i as u32), 10u64.pow(i as u32 + 1))
+                };
+                writeln!(f, "{:<20} {:<12}", range, self.opts.size(*bytes))?;
+            }
+        }
+        if stat.n_extents > 0 {
+            writeln!(f)?;
+            writeln!(
+                f,
+                "Average extent size {}.",
+                self.opts.size(stat.average_extent_size())
+            )?;
+            if let Some(median) = stat.median_extent_size_bucket() {
+                writeln!(
+                    f,
+                    "Median extent size {}.",
+                    extent_size_range(median, &self.opts)
+                )?;
+                writeln!(f, "{:<20} {:<12}", "Extent size", "Extents")?;
+                for (i, count) in stat.extent_sizes.iter().enumerate() {
+                    writeln!(f, "{:<20} {:<12}", extent_size_range(i, &self.opts), count)?;
+                }
+            }
+        }
+        if stat.fragmentation.iter().any(|&n| n > 0) {
+            writeln!(f)?;
+            writeln!(f, "{:<20} {:<12}", "Extents per file", "Files")?;
+            for (i, files) in stat.fragmentation.iter().enumerate() {
+                let range = if i + 1 == FRAGMENTATION_BUCKETS {
+                    format!(">= {}", FRAGMENTATION_BUCKET_BOUNDS[i - 1])
+                } else if i == 0 {
+                    "0".to_string()
+                } else if FRAGMENTATION_BUCKET_BOUNDS[i] - FRAGMENTATION_BUCKET_BOUNDS[i - 1] == 1 {
+                    FRAGMENTATION_BUCKET_BOUNDS[i - 1].to_string()
+                } else {
+                    format!(
+                        "{} - {}",
+                        FRAGMENTATION_BUCKET_BOUNDS[i - 1],
+                        FRAGMENTATION_BUCKET_BOUNDS[i] - 1
+                    )
+                };
+                writeln!(f, "{:<20} {:<12}", range, files)?;
+            }
+        }
+        if stat.ratio_files.iter().any(|&n| n > 0) {
+            writeln!(f)?;
+            writeln!(f, "{:<20} {:<12} {:<12}", "Ratio", "Files", "Disk Usage")?;
+            for (i, (files, bytes)) in stat.ratio_files.iter().zip(stat.ratio_bytes).enumerate() {
+                let range = if i == 0 {
+                    "n/a".to_string()
+                } else if i == 1 {
+                    format!("< {}x", RATIO_BUCKET_BOUNDS[0])
+                } else if i == RATIO_BUCKETS - 1 {
+                    format!(">= {}x", RATIO_BUCKET_BOUNDS[i - 2])
+                } else {
+                    format!(
+                        "{} - {}x",
+                        RATIO_BUCKET_BOUNDS[i - 2],
+                        RATIO_BUCKET_BOUNDS[i - 1]
+                    )
+                };
+                writeln!(
+                    f,
+                    "{:<20} {:<12} {:<12}",
+                    range,
+                    files,
+                    self.opts.size(bytes)
+                )?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// The sizes an [EXTENT_SIZE_BUCKETS] bucket holds.
+fn extent_size_range(bucket: usize, opts: &FormatOptions) -> String {
+    let low = MIN_BUCKETED_EXTENT_SIZE << bucket;
+    if bucket == 0 {
+        format!("< {}", opts.size(low << 1))
+    } else if bucket + 1 == EXTENT_SIZE_BUCKETS {
+        format!(">= {}", opts.size(low))
+    } else {
+        format!("{} - {}", opts.size(low), opts.size(low << 1))
+    }
+}
+
+/// The `schema_version` of [JsonFormatter], [NdjsonFormatter] and [YamlFormatter] output.
+///
+/// It's bumped whenever a field is renamed, removed or changes its meaning.
+/// Added fields don't bump it, so parsers should ignore fields they don't know.
+pub const JSON_SCHEMA_VERSION: u32 = 1;
+
+#[derive(Serialize)]
+struct Versioned<'a> {
+    schema_version: u32,
+    compviz_version: &'static str,
+    #[serde(flatten)]
+    stat: &'a Statistic,
+}
+impl<'a> Versioned<'a> {
+    fn new(stat: &'a Statistic) -> Self {
+        Self {
+            schema_version: JSON_SCHEMA_VERSION,
+            compviz_version: env!("CARGO_PKG_VERSION"),
+            stat,
+        }
+    }
+}
+
+/// The full [Statistic] as a single line of JSON, compression types keyed by their names,
+/// next to the `schema_version` and `compviz_version` fields.
+///
+/// The extra fields are ignored when reading it back as a [Statistic].
+/// With [FormatOptions::pretty] it's indented over several lines instead.
+#[derive(Debug, Default)]
+pub struct JsonFormatter {
+    pub opts: FormatOptions,
+}
+impl Formatter for JsonFormatter {
+    fn format(&self, stat: &Statistic, f: &mut dyn Write) -> io::Result<()> {
+        if self.opts.pretty {
+            serde_json::to_writer_pretty(&mut *f, &Versioned::new(stat))?;
+        } else {
+            serde_json::to_writer(&mut *f, &Versioned::new(stat))?;
+        }
+        writeln!(f)
+    }
+}
+
+/// The same fields as [JsonFormatter] as a YAML document.
+#[derive(Debug, Default)]
+pub struct YamlFormatter;
+impl Formatter for YamlFormatter {
+    fn format(&self, stat: &Statistic, f: &mut dyn Write) -> io::Result<()> {
+        serde_yaml::to_writer(f, &Versioned::new(stat)).map_err(io::Error::other)
+    }
+}
+
+#[derive(Serialize)]
+struct SummaryRecord<'a> {
+    summary: Versioned<'a>,
+}
+
+/// The closing line of `--format ndjson`, the whole statistic under a `summary` key
+/// so it can't be mistaken for a file's line.
+#[derive(Debug, Default)]
+pub struct NdjsonFormatter;
+impl Formatter for NdjsonFormatter {
+    fn format(&self, stat: &Statistic, f: &mut dyn Write) -> io::Result<()> {
+        let summary = Versioned::new(stat);
+        serde_json::to_writer(&mut *f, &SummaryRecord { summary })?;
+        writeln!(f)
+    }
+}
+
+/// The rows of the table as CSV, sizes always in bytes.
+///
+/// All fields are numbers or compression type names, so nothing needs quoting.
+#[derive(Debug, Default)]
+pub struct CsvFormatter {
+    pub opts: FormatOptions,
+}
+impl Formatter for CsvFormatter {
+    fn format(&self, stat: &Statistic, f: &mut dyn Write) -> io::Result<()> {
+        writeln!(
+            f,
+            "type,percent,disk_bytes,uncompressed_bytes,referenced_bytes,saved_bytes,extents,refs"
+        )?;
+        let total = stat.total();
+        let rows = sorted_rows(stat, self.opts.sort);
+        let rows = [("TOTAL".to_string(), &total)]
+            .into_iter()
+            .chain(rows.into_iter().map(|(c, info)| (c.to_string(), info)));
+        for (name, info) in rows {
+            writeln!(
+                f,
+                "{},{:.2},{},{},{},{},{},{}",
+                name,
+                info.compression_percent(),
+                info.disk_bytes,
+                info.uncompressed_bytes,
+                info.referenced_bytes,
+                info.saved(),
+                info.n_extents,
+                info.n_refs
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// The same table as [HumanFormatter] as a Markdown table, numbers aligned right.
+#[derive(Debug, Default)]
+pub struct MarkdownFormatter {
+    pub opts: FormatOptions,
+}
+impl Formatter for MarkdownFormatter {
+    fn format(&self, stat: &Statistic, f: &mut dyn Write) -> io::Result<()> {
+        writeln!(
+            f,
+            "| Type | Perc | Ratio | Disk Usage | Uncompressed | Referenced | Saved | Extents |"
+        )?;
+        writeln!(f, "|---|---:|---:|---:|---:|---:|---:|---:|")?;
+        let total = stat.total();
+        let rows = sorted_rows(stat, self.opts.sort);
+        let rows = [("TOTAL".to_string(), &total)]
+            .into_iter()
+            .chain(rows.into_iter().map(|(c, info)| (c.to_string(), info)));
+        for (name, info) in rows {
+            writeln!(
+                f,
+                "| {} | {:.2}% | {:.2}x | {} | {} | {} | {} | {} |",
+                name,
+                info.compression_percent(),
+                info.ratio(),
+                self.opts.size(info.disk_bytes),
+                self.opts.size(info.uncompressed_bytes),
+                self.opts.size(info.referenced_bytes),
+                self.opts.size(info.saved()),
+                info.n_extents
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// What compsize prints for the same statistic, down to its spacing, rounding and units.
+///
+/// Only [Units::Bytes] is respected, as compsize's `-b`. Unlike compsize, nothing is printed
+/// for no files instead of failing, and extents of unknown compression types are listed as
+/// `?N` like compsize does.
+#[derive(Debug, Default)]
+pub struct CompsizeFormatter {
+    pub opts: FormatOptions,
+}
+impl CompsizeFormatter {
+    /// compsize's `print_size`: up to 4 digits of the unit, or one decimal below 10 of it.
+    fn size(&self, bytes: usize) -> String {
+        if self.opts.units == Units::Bytes {
+            return bytes.to_string();
+        }
+        const UNITS: &[u8] = b"BKMGTPE";
+        let mut x = bytes as u64;
+        let mut u = 0;
+        while x >= 10240 {
+            u += 1;
+            x >>= 10;
+        }
+        if x >= 1024 {
+            format!(
+                " {}.{}{}",
+                x >> 10,
+                x * 10 / 1024 % 10,
+                UNITS[u + 1] as char
+            )
+        } else {
+            format!("{:4}{}", x, UNITS[u] as char)
+        }
+    }
+    fn row(&self, f: &mut dyn Write, name: &str, info: &ExtentInfo) -> io::Result<()> {
+        // compsize truncates to whole percents in integers, where 29 of 100 is 29 and not 28.99…
+        let percent = (info.disk_bytes as u64 * 100)
+            .checked_div(info.uncompressed_bytes as u64)
+            .unwrap_or(0);
+        writeln!(
+            f,
+            "{:<10} {:<8} {:<12} {:<12} {:<12}",
+            name,
+            format!("{percent:3}%"),
+            self.size(info.disk_bytes),
+            self.size(info.uncompressed_bytes),
+            self.size(info.referenced_bytes)
+        )
+    }
+}
+impl Formatter for CompsizeFormatter {
+    fn format(&self, stat: &Statistic, f: &mut dyn Write) -> io::Result<()> {
+        if stat.n_files == 0 {
+            return Ok(());
+        }
+        writeln!(
+            f,
+            "Processed {} file{}, {} regular extents ({} refs), {} inline.",
+            stat.n_files,
+            if stat.n_files > 1 { "s" } else { "" },
+            stat.n_extents,
+            stat.n_refs,
+            stat.n_inline
+        )?;
+        writeln!(
+            f,
+            "{:<10} {:<8} {:<12} {:<12} {:<12}",
+            "Type", "Perc", "Disk Usage", "Uncompressed", "Referenced"
+        )?;
+        self.row(f, "TOTAL", &stat.total())?;
+        for (compression, info) in &stat.extent_info {
+            // compsize leaves out types without any data
+            if info.uncompressed_bytes == 0 {
+                continue;
+            }
+            let name = match compression {
+                CompressionType::Unknown(v) => format!("?{v}"),
+                c => c.to_string(),
+            };
+            self.row(f, &name, info)?;
+        }
+        Ok(())
+    }
+}
+
+/// Metrics in the Prometheus text exposition format, byte gauges labeled by compression type
+/// and gauges of the counts.
+#[derive(Debug, Default)]
+pub struct PrometheusFormatter;
+impl Formatter for PrometheusFormatter {
+    fn format(&self, stat: &Statistic, f: &mut dyn Write) -> io::Result<()> {
+        type Field = fn(&ExtentInfo) -> usize;
+        let bytes: [(&str, &str, Field); 3] = [
+            ("compviz_disk_bytes", "Bytes used on disk.", |i| {
+                i.disk_bytes
+            }),
+            (
+                "compviz_uncompressed_bytes",
+                "Bytes the extents would take uncompressed.",
+                |i| i.uncompressed_bytes,
+            ),
+            (
+                "compviz_referenced_bytes",
+                "Bytes referenced by files, counting shared extents for every reference.",
+                |i| i.referenced_bytes,
+            ),
+        ];
+        for (name, help, value) in bytes {
+            writeln!(f, "# HELP {name} {help}")?;
+            writeln!(f, "# TYPE {name} gauge")?;
+            for (compression, info) in &stat.extent_info {
+                writeln!(f, "{name}{{compression=\"{compression}\"}} {}", value(info))?;
+            }
+        }
+        let counts = [
+            ("compviz_files", "Files scanned.", stat.n_files),
+            ("compviz_extents", "Unique regular extents.", stat.n_extents),
+            (
+                "compviz_refs",
+                "References to regular extents.",
+                stat.n_refs,
+            ),
+            ("compviz_inline", "Inline extents.", stat.n_inline),
+        ];
+        // counts of a single scan, which may go down in the next one, so no `_total` counters
+        for (name, help, value) in counts {
+            writeln!(f, "# HELP {name} {help}")?;
+            writeln!(f, "# TYPE {name} gauge")?;
+            writeln!(f, "{name} {value}")?;
+        }
+        Ok(())
+    }
+}
+
+/// InfluxDB line protocol: a `compviz` line tagged with each compression type,
+/// then a `compviz_total` line with the totals and counts.
+///
+/// Lines end with a nanosecond timestamp only with [FormatOptions::timestamp].
+#[derive(Debug, Default)]
+pub struct InfluxFormatter {
+    pub opts: FormatOptions,
+}
+impl Formatter for InfluxFormatter {
+    fn format(&self, stat: &Statistic, f: &mut dyn Write) -> io::Result<()> {
+        let timestamp = if self.opts.timestamp {
+            let now = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_err(io::Error::other)?;
+            format!(" {}", now.as_nanos())
+        } else {
+            String::new()
+        };
+        let fields = |info: &ExtentInfo| {
+            format!(
+                "disk_bytes={}i,uncompressed_bytes={}i,referenced_bytes={}i",
+                info.disk_bytes, info.uncompressed_bytes, info.referenced_bytes
+            )
+        };
+        for (compression, info) in &stat.extent_info {
+            // names have no spaces, commas or equal signs that would need escaping as tags
+            writeln!(
+                f,
+                "compviz,compression={compression} {}{timestamp}",
+                fields(info)
+            )?;
+        }
+        writeln!(
+            f,
+            "compviz_total {},files={}i,extents={}i,refs={}i,inline={}i{timestamp}",
+            fields(&stat.total()),
+            stat.n_files,
+            stat.n_extents,
+            stat.n_refs,
+            stat.n_inline
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Three files, one uncompressed extent and 4MiB compressed to 1MiB with zstd.
+    fn stat() -> Statistic {
+        let mut stat = Statistic {
+            n_files: 3,
+            n_extents: 9,
+            n_refs: 11,
+            n_inline: 1,
+            ..Default::default()
+        };
+        stat.extent_info.insert(
+            CompressionType::None,
+            ExtentInfo {
+                disk_bytes: 4096,
+                uncompressed_bytes: 4096,
+                referenced_bytes: 4096,
+                n_extents: 1,
+                n_refs: 1,
+            },
+        );
+        stat.extent_info.insert(
+            CompressionType::Zstd,
+            ExtentInfo {
+                disk_bytes: 1 << 20,
+                uncompressed_bytes: 4 << 20,
+                referenced_bytes: 4 << 20,
+                n_extents: 8,
+                n_refs: 10,
+            },
+        );
+        stat
+    }
+
+    fn render(formatter: impl Formatter, stat: &Statistic) -> String {
+        let mut out = Vec::new();
+        formatter.format(stat, &mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    fn human(units: Units) -> HumanFormatter {
+        HumanFormatter {
+            opts: FormatOptions {
+                units,
+                ..Default::default()
+            },
+        }
+    }
+
+    #[test]
+    fn human_table_rows_in_type_order() {
+        let out = render(human(Units::Binary), &stat());
+        assert_eq!(out, render(human(Units::Binary), &stat()));
+        let types: Vec<_> = out
+            .lines()
+            .skip_while(|line| !line.starts_with("Type"))
+            .skip(1)
+            .take(3)
+            .map(|line| line.split_whitespace().next().unwrap())
+            .collect();
+        assert_eq!(types, ["TOTAL", "none", "zstd"]);
+    }
+
+    #[test]
+    fn human_table_without_data_has_no_nan() {
+        let mut stat = Statistic {
+            n_files: 2,
+            ..Default::default()
+        };
+        stat.extent_info
+            .insert(CompressionType::None, ExtentInfo::default());
+        let out = render(human(Units::Binary), &stat);
+        assert!(out.contains("0.00%"), "{out}");
+        assert!(!out.contains("NaN") && !out.contains("inf"), "{out}");
+    }
+
+    #[test]
+    fn human_table_units() {
+        let zstd_row = |units| {
+            render(human(units), &stat())
+                .lines()
+                .find(|line| line.starts_with("zstd"))
+                .unwrap()
+                .to_string()
+        };
+        assert!(zstd_row(Units::Binary).contains("1 MiB"));
+        assert!(zstd_row(Units::Decimal).contains("1.05 MB"));
+        let bytes = zstd_row(Units::Bytes);
+        let cells: Vec<_> = bytes.split_whitespace().collect();
+        assert_eq!(cells[3..7], ["1048576", "4194304", "4194304", "3145728"]);
+    }
+
+    #[test]
+    fn csv_parses_back() {
+        let out = render(CsvFormatter::default(), &stat());
+        let mut lines = out.lines();
+        let header: Vec<_> = lines.next().unwrap().split(',').collect();
+        assert_eq!(header[0], "type");
+        let rows: Vec<Vec<_>> = lines.map(|line| line.split(',').collect()).collect();
+        let types: Vec<_> = rows.iter().map(|row| row[0]).collect();
+        assert_eq!(types, ["TOTAL", "none", "zstd"]);
+        for row in &rows {
+            assert_eq!(row.len(), header.len());
+            for cell in &row[1..] {
+                assert!(cell.parse::<f64>().is_ok(), "{cell:?}");
+            }
+        }
+        assert_eq!(rows[2][2], "1048576");
+    }
+
+    #[test]
+    fn markdown_separator_matches_header() {
+        let out = render(MarkdownFormatter::default(), &stat());
+        let mut lines = out.lines();
+        let columns = |line: &str| line.matches('|').count();
+        let header = columns(lines.next().unwrap());
+        assert_eq!(columns(lines.next().unwrap()), header);
+        assert!(lines.all(|line| columns(line) == header));
+    }
+
+    #[test]
+    fn prometheus_lines_parse() {
+        let out = render(PrometheusFormatter, &stat());
+        assert!(out.ends_with('\n'));
+        let mut declared = BTreeSet::new();
+        for line in out.lines() {
+            if let Some(comment) = line.strip_prefix("# ") {
+                let mut words = comment.split(' ');
+                let (kind, name) = (words.next().unwrap(), words.next().unwrap());
+                if kind == "TYPE" {
+                    assert_eq!(words.next(), Some("gauge"));
+                    declared.insert(name.to_string());
+                }
+                continue;
+            }
+            let (metric, value) = line.rsplit_once(' ').unwrap();
+            value.parse::<u64>().unwrap();
+            let name = metric.split('{').next().unwrap();
+            assert!(declared.contains(name), "{name} has no TYPE");
+            assert!(!name.ends_with("_total"), "{name} is a gauge");
+        }
+        assert!(out.contains("compviz_disk_bytes{compression=\"zstd\"} 1048576\n"));
+        assert!(out.contains("compviz_files 3\n"));
+    }
+
+    #[test]
+    fn influx_lines_parse() {
+        let out = render(InfluxFormatter::default(), &stat());
+        let lines: Vec<_> = out.lines().collect();
+        assert_eq!(lines.len(), 3);
+        for line in &lines {
+            // no timestamp without FormatOptions::timestamp
+            let (series, fields) = line.split_once(' ').unwrap();
+            assert!(!fields.contains(' '), "{line}");
+            assert!(series.starts_with("compviz"));
+            for field in fields.split(',') {
+                let (_, value) = field.split_once('=').unwrap();
+                value.strip_suffix('i').unwrap().parse::<u64>().unwrap();
+            }
+        }
+        assert_eq!(
+            lines[1],
+            "compviz,compression=zstd disk_bytes=1048576i,uncompressed_bytes=4194304i,referenced_bytes=4194304i"
+        );
+        assert!(lines[2].starts_with("compviz_total disk_bytes=1052672i,"));
+    }
+
+    #[test]
+    fn json_round_trip() {
+        let out = render(JsonFormatter::default(), &stat());
+        assert!(out.starts_with(&format!("{{\"schema_version\":{JSON_SCHEMA_VERSION},")));
+        let parsed: Statistic = serde_json::from_str(&out).unwrap();
+        assert_eq!(parsed.n_files, 3);
+        assert_eq!(
+            parsed.extent_info[&CompressionType::Zstd].disk_bytes,
+            1 << 20
+        );
+        assert_eq!(
+            parsed.total().referenced_bytes,
+            stat().total().referenced_bytes
+        );
+    }
+
+    #[test]
+    fn yaml_round_trip() {
+        let out = render(YamlFormatter, &stat());
+        let parsed: Statistic = serde_yaml::from_str(&out).unwrap();
+        assert_eq!(parsed.n_refs, 11);
+        assert_eq!(parsed.extent_info[&CompressionType::None].n_extents, 1);
+    }
+}
@@ -0,0 +1,86 @@
+use std::{
+    cmp::Reverse,
+    ffi::OsStr,
+    io::{self, Read, Write},
+    os::unix::ffi::OsStrExt,
+    path::Path,
+};
+
+use anyhow::bail;
+use serde::{Deserialize, Serialize};
+
+use crate::Statistic;
+
+const MAGIC: &[u8; 4] = b"CVIX";
+/// Bumped whenever the layout of [Index] changes, as bincode is not self describing.
+/// That includes any field added to or removed from [IndexEntry] or the [Statistic] within.
+pub const VERSION: u32 = 1;
+
+/// Per-file statistics of a scan, so that different views can be computed without rescanning.
+///
+/// On disk it's [MAGIC], [VERSION] as little endian u32, then the bincode encoded struct.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Index {
+    pub files: Vec<IndexEntry>,
+}
+#[derive(Debug, Serialize, Deserialize)]
+pub struct IndexEntry {
+    pub ino: u64,
+    /// The raw bytes of [IndexEntry::path()], as serde refuses paths that aren't UTF-8.
+    pub path: Vec<u8>,
+    /// Statistic of this file alone. Extents already seen in another file are not counted
+    /// towards disk bytes here, so the entries sum up to the deduplicated total.
+    pub stat: Statistic,
+}
+impl IndexEntry {
+    pub fn new(ino: u64, path: &Path, stat: Statistic) -> Self {
+        IndexEntry {
+            ino,
+            path: path.as_os_str().as_bytes().to_vec(),
+            stat,
+        }
+    }
+    pub fn path(&self) -> &Path {
+        Path::new(OsStr::from_bytes(&self.path))
+    }
+}
+impl Index {
+    pub fn write_to(&self, mut writer: impl Write) -> anyhow::Result<()> {
+        writer.write_all(MAGIC)?;
+        writer.write_all(&VERSION.to_le_bytes())?;
+        bincode::serialize_into(writer, self)?;
+        Ok(())
+    }
+    pub fn read_from(mut reader: impl Read) -> anyhow::Result<Self> {
+        let mut header = [0; 8];
+        reader
+            .read_exact(&mut header)
+            .map_err(|err| match err.kind() {
+                io::ErrorKind::UnexpectedEof => io::Error::new(err.kind(), "not a compviz index"),
+                _ => err,
+            })?;
+        if &header[..4] != MAGIC {
+            bail!("not a compviz index");
+        }
+        let version = u32::from_le_bytes(header[4..].try_into().unwrap());
+        if version != VERSION {
+            bail!("unsupported index version {version}, expected {VERSION}");
+        }
+        Ok(bincode::deserialize_from(reader)?)
+    }
+    /// The `n` files using the most disk, largest first, as `--top` lists them.
+    pub fn largest(&self, n: usize) -> Vec<&IndexEntry> {
+        let mut files: Vec<_> = self.files.iter().collect();
+        files.sort_by_cached_key(|entry| Reverse(entry.stat.total().disk_bytes));
+        files.truncate(n);
+        files
+    }
+    /// The aggregate statistic of all files, as a scan would have reported it.
+    pub fn statistic(&self) -> Statistic {
+        let mut stat = Statistic::default();
+        for entry in &self.files {
+            stat += &entry.stat;
+        }
+        stat
+    }
+}
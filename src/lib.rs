@@ -0,0 +1,26 @@
+// Everything reading the filesystem relies on Linux ioctls and btrfs headers, fail with
+// a single clear error rather than with all the errors of compiling that elsewhere.
+#[cfg(not(target_os = "linux"))]
+compile_error!("compviz only supports Linux, as it reads btrfs metadata through Linux ioctls");
+
+#[cfg(target_os = "linux")]
+mod bloom;
+#[cfg(target_os = "linux")]
+pub mod btrfs;
+mod error;
+#[cfg(target_os = "linux")]
+mod ffi;
+pub mod format;
+pub mod index;
+#[cfg(target_os = "linux")]
+mod scan;
+mod stat;
+
+pub use error::CompvizError;
+#[cfg(target_os = "linux")]
+pub use scan::{
+    analyze, count_files, default_num_threads, handle_interrupts, interrupted, is_excluded, scan,
+    stat_inode, AnalyzeOptions, ApproxDedup, DefragCandidate, DefragThresholds, FileCallback,
+    FileFn, GroupBy, ScanError, ScanResult, TopFile,
+};
+pub use stat::*;
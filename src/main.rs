@@ -1,255 +1,399 @@
-use core::fmt;
 use std::{
-    cell::RefCell,
-    collections::{HashMap, HashSet},
+    collections::HashMap,
     env,
-    fmt::Display,
+    ffi::OsString,
     fs::{self, File},
-    ops::AddAssign,
-    os::unix::fs::MetadataExt,
-    path::Path,
-    sync::{Arc, Mutex},
+    io::{self, BufRead, IsTerminal, Write},
+    os::unix::ffi::OsStringExt,
+    path::{Path, PathBuf},
+    process::ExitCode,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
 };
 
-use anyhow::anyhow;
-use humansize::{FormatSize, BINARY};
-mod btrfs;
-mod ffi;
+use anyhow::bail;
+use clap::Parser;
+use cli::{Cli, Output};
+use compviz::{
+    btrfs::SearchArgs,
+    format::{diff_table, group_table, per_file_line, FormatOptions, OutputFormat},
+    index, AnalyzeOptions, GroupBy, ScanError, ScanResult, Statistic, TopFile,
+};
+use indicatif::{ProgressBar, ProgressStyle};
+use tracing_subscriber::EnvFilter;
+mod cli;
+mod webhook;
 
-#[derive(Debug, Default)]
-struct ExtentInfo {
-    pub disk_bytes: usize,
-    pub uncompressed_bytes: usize,
-    pub referenced_bytes: usize,
-}
-impl ExtentInfo {
-    pub fn compression_percent(&self) -> f64 {
-        (self.disk_bytes as f64 / self.uncompressed_bytes as f64) * 100.0
+fn main() -> anyhow::Result<ExitCode> {
+    let cli = Cli::parse();
+    let filter = match &cli.log_level {
+        Some(level) => EnvFilter::try_new(level)?,
+        None => EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("off")),
+    };
+    tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(io::stderr)
+        .init();
+    if cli.pretty && cli.output_format() != OutputFormat::Json {
+        eprintln!("Warning: --pretty only applies to JSON output, ignoring it");
+    }
+    // the extra lists and tables are laid out like the human table, they'd break any other format
+    if cli.output_format() != OutputFormat::Human {
+        let human_only = [
+            ("--top", cli.top.is_some()),
+            ("--by-extension", cli.by_extension),
+            ("--by-dir", cli.by_dir),
+            ("--diff", cli.diff.is_some()),
+        ];
+        if let Some((flag, _)) = human_only.iter().find(|(_, given)| *given) {
+            bail!("{flag} only applies to --format human");
+        }
+    }
+    // read before scanning, so a bad baseline doesn't waste a long scan
+    let baseline: Option<Statistic> = match &cli.diff {
+        Some(diff) => Some(serde_json::from_reader(io::BufReader::new(File::open(
+            diff,
+        )?))?),
+        None => None,
+    };
+    let paths = if cli.paths_from_stdin() {
+        let delimiter = if cli.null { b'\0' } else { b'\n' };
+        let mut paths = read_paths(io::stdin().lock(), delimiter)?;
+        // exactly the files listed are scanned, as a directory from e.g. `find` would be walked
+        // again for each of its entries listed after it. Unreadable ones are left to be reported.
+        paths.retain(|path| fs::metadata(path).map_or(true, |metadata| metadata.is_file()));
+        if paths.is_empty() {
+            bail!("no files to scan were given on stdin");
+        }
+        paths
+    } else {
+        cli.paths.clone()
+    };
+    let Some(interval) = cli.watch else {
+        return run(&cli, &paths, baseline.as_ref());
+    };
+    compviz::handle_interrupts()?;
+    // a file or pipe would collect the escapes between the reports
+    let clear = cli.output.is_none() && io::stdout().is_terminal();
+    loop {
+        if clear {
+            // clear the screen and move to its top left corner
+            print!("\x1b[2J\x1b[H");
+        }
+        let code = run(&cli, &paths, baseline.as_ref())?;
+        // honor the interval, but stop watching as soon as Ctrl-C is pressed
+        let next = Instant::now() + Duration::from_secs(interval);
+        while !compviz::interrupted() && Instant::now() < next {
+            thread::sleep(Duration::from_millis(100));
+        }
+        if compviz::interrupted() {
+            return Ok(code);
+        }
     }
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
-struct CompressionType(u8);
-impl CompressionType {
-    pub fn iter() -> impl Iterator<Item = CompressionType> {
-        (u8::MIN..u8::MAX).map(CompressionType)
+/// Scan `paths` or read a previous result as asked by `cli`, then print everything about it.
+fn run(cli: &Cli, paths: &[PathBuf], baseline: Option<&Statistic>) -> anyhow::Result<ExitCode> {
+    let out: Output = match &cli.output {
+        Some(output) => Arc::new(Mutex::new(io::BufWriter::new(File::create(output)?))),
+        None => Arc::new(Mutex::new(io::stdout())),
+    };
+    let mut failed_roots = 0;
+    let mut errors = Vec::new();
+    let mut top = Vec::new();
+    let mut defrag_candidates = Vec::new();
+    let mut groups = HashMap::new();
+    let mut elapsed = None;
+    let mut interrupted = false;
+    let stat = if let Some(replay) = &cli.replay {
+        serde_json::from_reader(io::BufReader::new(File::open(replay)?))?
+    } else if let Some(from_index) = &cli.from_index {
+        let index = index::Index::read_from(io::BufReader::new(File::open(from_index)?))?;
+        // the index has the statistic of every file, so the views a scan collects can be had too
+        if let Some(n) = cli.top.filter(|_| cli.group_by().is_none()) {
+            top = index
+                .largest(n)
+                .into_iter()
+                .map(|entry| TopFile {
+                    disk_bytes: entry.stat.total().disk_bytes,
+                    path: entry.path().to_owned(),
+                    stat: entry.stat.clone(),
+                })
+                .collect();
+        }
+        // --by-dir is rejected, the given paths the directories are below aren't in the index
+        if let Some(by) = cli.group_by() {
+            for entry in &index.files {
+                *groups
+                    .entry(by.key(entry.path(), Path::new("")))
+                    .or_default() += &entry.stat;
+            }
+        }
+        index.statistic()
+    } else if let Some(ino) = cli.inode {
+        let [path] = paths else {
+            bail!("--inode takes a single path to find the subvolume by");
+        };
+        compviz::stat_inode(path, ino, &cli.analyze_options(out.clone()))?
+    } else {
+        let mut opts = cli.analyze_options(out.clone());
+        let mut threads_from = "--threads";
+        // an explicit RAYON_NUM_THREADS wins over the heuristic, but not over --threads
+        if opts.threads.is_none() {
+            if let Ok(Ok(threads)) = env::var("RAYON_NUM_THREADS").map(|s| s.parse()) {
+                opts.threads = Some(threads);
+                threads_from = "RAYON_NUM_THREADS";
+            }
+        }
+        if cli.verbose {
+            print_config(&opts, threads_from, &cli.format_options());
+        }
+        compviz::handle_interrupts()?;
+        let start = Instant::now();
+        let result = if cli.progress {
+            scan_with_progress(paths, opts)?
+        } else {
+            compviz::scan(paths, &opts)?
+        };
+        elapsed = Some(start.elapsed());
+        // a table of nothing won't help
+        if result.all_failed() {
+            report_errors(&result.errors, cli.errors);
+            return Ok(ExitCode::FAILURE);
+        }
+        if let Some(dump_index) = &cli.dump_index {
+            let mut writer = io::BufWriter::new(File::create(dump_index)?);
+            index::Index {
+                files: result.index,
+            }
+            .write_to(&mut writer)?;
+            writer.flush()?;
+        }
+        for path in &result.skipped {
+            eprintln!("Skipped non-regular file {}", path.display());
+        }
+        if cli.verbose {
+            let size = cli.format_options().size(result.dedup_bytes);
+            match result.dedup_extents {
+                Some(n) => eprintln!("dedup set: {n} unique extents (~{size})"),
+                None => eprintln!("dedup filter: {size}"),
+            }
+        }
+        failed_roots = result.failed_roots;
+        errors = result.errors;
+        top = result.top;
+        defrag_candidates = result.defrag_candidates;
+        groups = result.groups;
+        interrupted = result.interrupted;
+        result.stat
+    };
+    let mut out = out.lock().unwrap();
+    let out: &mut dyn Write = &mut *out;
+    let format_options = cli.format_options();
+    if cli.defrag_candidates {
+        for file in &defrag_candidates {
+            writeln!(
+                out,
+                "{}\t{}\t{:.2}",
+                file.path.display(),
+                file.n_extents,
+                file.ratio
+            )?;
+        }
+    } else {
+        for file in &top {
+            writeln!(
+                out,
+                "{}",
+                per_file_line(&file.path, &file.stat, &format_options)
+            )?;
+        }
+        if !top.is_empty() {
+            writeln!(out)?;
+        }
+        cli.output_format()
+            .formatter(format_options.clone())
+            .format(&stat, out)?;
+        if let Some(group_by) = cli.group_by() {
+            let title = match group_by {
+                GroupBy::Extension => "Extension",
+                GroupBy::TopDir => "Directory",
+            };
+            writeln!(out)?;
+            group_table(out, title, &groups, &stat, cli.top, &format_options)?;
+        }
+        if let Some(baseline) = baseline {
+            writeln!(out)?;
+            diff_table(out, baseline, &stat, &format_options)?;
+        }
     }
-}
-impl fmt::Display for CompressionType {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(
-            f,
-            "{}",
-            match self.0 {
-                0 => "none",
-                1 => "zlib",
-                2 => "lzo",
-                3 => "zstd",
-                _ => return write!(f, "unknown({})", self.0),
+    out.flush()?;
+    if let Some(save) = &cli.save {
+        let mut writer = io::BufWriter::new(File::create(save)?);
+        serde_json::to_writer(&mut writer, &stat)?;
+        writer.flush()?;
+    }
+    if let Some(elapsed) = elapsed.filter(|_| cli.timing) {
+        // no rate to speak of when it took no measurable time, e.g. with nothing to scan
+        let rate = if elapsed.is_zero() {
+            "n/a".to_string()
+        } else {
+            format!("{:.0}", stat.n_files as f64 / elapsed.as_secs_f64())
+        };
+        eprintln!(
+            "Scanned {} files in {:.2?}, {rate} files/s",
+            stat.n_files, elapsed
+        );
+    }
+    if let Some(url) = &cli.webhook {
+        if let Err(err) = webhook::send(url, &cli.webhook_header, paths, &stat) {
+            if cli.webhook_required {
+                return Err(err);
             }
-        )
+            eprintln!("Warning: {}", err);
+        }
+    }
+    report_errors(&errors, cli.errors);
+    if failed_roots > 0 {
+        eprintln!(
+            "Error: {} of {} paths could not be scanned",
+            failed_roots,
+            paths.len() + cli.snapshot.len()
+        );
+    }
+    if interrupted {
+        eprintln!("Interrupted, the statistic only covers what was scanned until then");
+        // as if killed by SIGINT
+        return Ok(ExitCode::from(130));
     }
+    if !errors.is_empty() {
+        return Ok(ExitCode::FAILURE);
+    }
+    Ok(ExitCode::SUCCESS)
 }
-#[derive(Debug, Default)]
-struct Statistic {
-    pub extent_info: HashMap<CompressionType, ExtentInfo>,
-    pub n_files: usize,
-    pub n_extents: usize,
-    pub n_refs: usize,
-    pub n_inline: usize,
+
+/// [compviz::scan] while drawing a progress bar on stderr.
+fn scan_with_progress(paths: &[PathBuf], mut opts: AnalyzeOptions) -> anyhow::Result<ScanResult> {
+    let total = compviz::count_files(paths, &opts)?;
+    let progress = Arc::new(AtomicUsize::new(0));
+    opts.progress = Some(progress.clone());
+    let bar = ProgressBar::new(total as u64).with_style(ProgressStyle::with_template(
+        "{wide_bar} {pos}/{len} files, {elapsed} elapsed, {eta} left",
+    )?);
+    let done = AtomicBool::new(false);
+    let result = thread::scope(|s| {
+        s.spawn(|| {
+            while !done.load(Ordering::Relaxed) {
+                bar.set_position(progress.load(Ordering::Relaxed) as u64);
+                thread::sleep(Duration::from_millis(100));
+            }
+        });
+        let result = compviz::scan(paths, &opts);
+        done.store(true, Ordering::Relaxed);
+        result
+    });
+    bar.finish_and_clear();
+    result
 }
 
-impl AddAssign<&Statistic> for Statistic {
-    fn add_assign(&mut self, rhs: &Statistic) {
-        self.n_files += rhs.n_files;
-        self.n_extents += rhs.n_extents;
-        self.n_refs += rhs.n_refs;
-        self.n_inline += rhs.n_inline;
-        for (compression, info) in rhs.extent_info.iter() {
-            let self_info = self.extent_info.entry(*compression).or_default();
-            self_info.disk_bytes += info.disk_bytes;
-            self_info.uncompressed_bytes += info.uncompressed_bytes;
-            self_info.referenced_bytes += info.referenced_bytes;
+/// Print what the scan is tuned with, the choices of the heuristics included.
+fn print_config(opts: &AnalyzeOptions, threads_from: &str, format_options: &FormatOptions) {
+    let threads = match opts.threads {
+        Some(threads) => format!("{threads} (from {threads_from})"),
+        None => {
+            let cpus = thread::available_parallelism().map_or(1, |n| n.get());
+            format!("{} (for {cpus} CPUs)", compviz::default_num_threads())
         }
-    }
+    };
+    eprintln!("threads: {threads}");
+    let buf_size = opts.buf_size.unwrap_or(SearchArgs::DEFAULT_BUF_SIZE);
+    eprintln!("buffer size: {}", format_options.size(buf_size));
+    let dedup = match opts.approx_dedup {
+        _ if opts.no_dedup => "off".to_string(),
+        Some(approx) => format!(
+            "approximate, {} bloom filter at a {}% false positive rate",
+            format_options.size(approx.memory),
+            approx.fp_rate * 100.0
+        ),
+        None => "exact".to_string(),
+    };
+    eprintln!("dedup: {dedup}");
 }
-impl Statistic {
-    pub fn table(&self) -> impl Display + '_ {
-        struct T<'a>(&'a Statistic);
-        impl Display for T<'_> {
-            fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-                writeln!(
-                    f,
-                    "Processed {} files, {} regular extents ({} refs), {} inline.",
-                    self.0.n_files, self.0.n_extents, self.0.n_refs, self.0.n_inline
-                )?;
-                macro_rules! print_table {
-                    ($f:expr, $col1:expr, $col2:expr, $col3:expr, $col4:expr, $col5:expr) => {
-                        writeln!(
-                            $f,
-                            "{:<10} {:<8} {:<12} {:<12} {:<12}",
-                            $col1, $col2, $col3, $col4, $col5
-                        )?;
-                    };
-                }
-                print_table!(
-                    f,
-                    "Type",
-                    "Perc",
-                    "Disk Usage",
-                    "Uncompressed",
-                    "Referenced"
-                );
-                let total =
-                    self.0
-                        .extent_info
-                        .values()
-                        .fold(ExtentInfo::default(), |mut acc, e| {
-                            acc.disk_bytes += e.disk_bytes;
-                            acc.uncompressed_bytes += e.uncompressed_bytes;
-                            acc.referenced_bytes += e.referenced_bytes;
-                            acc
-                        });
-
-                let percent = format!("{:.2}%", total.compression_percent());
 
-                print_table!(
-                    f,
-                    "TOTAL",
-                    percent,
-                    total.disk_bytes.format_size(BINARY),
-                    total.uncompressed_bytes.format_size(BINARY),
-                    total.referenced_bytes.format_size(BINARY)
-                );
-                for compression in CompressionType::iter() {
-                    let Some(info) = self.0.extent_info.get(&compression) else {
-                        continue;
-                    };
-                    let percent = format!("{:.2}%", info.compression_percent());
-                    print_table!(
-                        f,
-                        compression.to_string(),
-                        percent,
-                        info.disk_bytes.format_size(BINARY),
-                        info.uncompressed_bytes.format_size(BINARY),
-                        info.referenced_bytes.format_size(BINARY)
-                    );
-                }
-
-                Ok(())
-            }
+/// Read paths separated by `delimiter` from `reader`, skipping empty ones.
+///
+/// Paths are taken byte for byte, so they needn't be valid UTF-8.
+fn read_paths(reader: impl BufRead, delimiter: u8) -> io::Result<Vec<PathBuf>> {
+    let mut paths = Vec::new();
+    for path in reader.split(delimiter) {
+        let path = path?;
+        if !path.is_empty() {
+            paths.push(PathBuf::from(OsString::from_vec(path)));
         }
-        T(self)
     }
+    Ok(paths)
 }
 
-struct FileExtentsEnumerator {
-    args: btrfs::btrfs_ioctl_search_args_v2_64KB,
-    seen_extents: Arc<Mutex<HashSet<u64>>>,
-    stat: Statistic,
-}
-impl FileExtentsEnumerator {
-    pub fn with_shared(seen_extents: Arc<Mutex<HashSet<u64>>>) -> Self {
-        Self {
-            args: btrfs::btrfs_ioctl_search_args_v2_64KB::new_search_file_extent_data(0),
-            stat: Statistic::default(),
-            seen_extents,
-        }
-    }
-    pub fn work_on_file(
-        &mut self,
-        path: impl AsRef<Path>,
-        file_type: fs::FileType,
-    ) -> anyhow::Result<()> {
-        let path = path.as_ref();
-        if file_type.is_file() {
-            self.stat.n_files += 1;
-            let f = File::open(path)?;
-            self.args.set_search_file_extent_data(f.metadata()?.ino());
-            let mut iter = btrfs::get_file_extents_with(f, &mut self.args)?;
-            for extent in iter.into_iter() {
-                let extent = extent?;
-                let info = self
-                    .stat
-                    .extent_info
-                    .entry(CompressionType(extent.compression()))
-                    .or_default();
-                if extent.type_() == btrfs::BtrfsFileExtentType::Inline {
-                    info.disk_bytes += extent.disk_num_bytes() as usize;
-                    info.uncompressed_bytes += extent.ram_bytes() as usize;
-                    info.referenced_bytes += extent.ram_bytes() as usize;
-                    self.stat.n_inline += 1;
-                    return Ok(());
-                }
-                // okay to unwrap as only INLINE extents will have a None, and we return early
-                if self
-                    .seen_extents
-                    .lock()
-                    .unwrap()
-                    .insert(extent.disk_bytenr().unwrap())
-                {
-                    info.disk_bytes += extent.disk_num_bytes() as usize;
-                    info.uncompressed_bytes += extent.ram_bytes() as usize;
-                    self.stat.n_extents += 1;
-                }
-                info.referenced_bytes += extent.num_bytes() as usize;
-                self.stat.n_refs += 1;
-            }
-        } else if file_type.is_dir() {
-            for entry in fs::read_dir(path)? {
-                let entry = entry?;
-                let file_type = entry.file_type()?;
-                rayon::spawn(move || {
-                    T_ENUMRATOR.with_borrow_mut(|e| {
-                        if let Err(err) = e.work_on_file(entry.path(), file_type) {
-                            eprintln!("Error: {}", err);
-                        }
-                    })
-                });
-            }
-        }
-        Ok(())
+/// How many errors are shown without `--errors`.
+const ERROR_EXAMPLES: usize = 5;
+
+/// Summarize the collected errors on stderr, listing all of them if `all` is set.
+fn report_errors(errors: &[ScanError], all: bool) {
+    if errors.is_empty() {
+        return;
+    }
+    eprintln!("Error: {} files could not be read", errors.len());
+    let shown = if all { errors.len() } else { ERROR_EXAMPLES };
+    for err in errors.iter().take(shown) {
+        eprintln!("  {}: {}", err.path.display(), err.error);
+    }
+    if errors.len() > shown {
+        eprintln!(
+            "  ... {} more, pass --errors to list all of them",
+            errors.len() - shown
+        );
+    }
+    let permission_denied = errors.iter().filter(|e| e.is_permission_denied()).count();
+    if permission_denied > 0 {
+        eprintln!(
+            "Hint: {} of them were denied permission; try running as root or with CAP_SYS_ADMIN",
+            permission_denied
+        );
     }
 }
-thread_local! {
-    static T_ENUMRATOR: RefCell<FileExtentsEnumerator> = panic!("thread local enumrator not initialized");
-}
-fn main() -> anyhow::Result<()> {
-    let stat = Mutex::new(Statistic::default());
-    let shared_hashset = Arc::new(Mutex::new(HashSet::new()));
-    rayon::ThreadPoolBuilder::new()
-        .num_threads(
-            if let Ok(Ok(env_var)) = env::var("RAYON_NUM_THREADS").map(|s| s.parse()) {
-                env_var
-            } else {
-                let cpus = std::thread::available_parallelism()
-                    .map(|x| x.get())
-                    .unwrap_or(1);
-                match cpus {
-                    0..=6 => cpus,
-                    24..usize::MAX => 24,
-                    _ => cpus / 2 + 1,
-                }
-            },
-        )
-        .build_scoped(
-            |thread| {
-                T_ENUMRATOR.set(FileExtentsEnumerator::with_shared(shared_hashset.clone()));
-                thread.run();
-                T_ENUMRATOR.with_borrow(|e| {
-                    *stat.lock().unwrap() += &e.stat;
-                });
-            },
-            |pool| {
-                pool.install(|| -> anyhow::Result<()> {
-                    let path = std::env::args()
-                        .nth(1)
-                        .ok_or_else(|| anyhow!("Missing argument"))?;
-                    let metadata: fs::Metadata = fs::metadata(&path)?;
-                    T_ENUMRATOR.with_borrow_mut(|e| e.work_on_file(path, metadata.file_type()))
-                })
-            },
-        )??;
-    println!("{}", stat.lock().unwrap().table());
-    Ok(())
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn paths(input: &[u8], delimiter: u8) -> Vec<PathBuf> {
+        read_paths(input, delimiter).unwrap()
+    }
+
+    #[test]
+    fn read_paths_by_newline() {
+        let expected = [PathBuf::from("/a b"), PathBuf::from("c")];
+        assert_eq!(paths(b"/a b\nc", b'\n'), expected);
+        // with a trailing separator and empty lines in between
+        assert_eq!(paths(b"\n/a b\n\n\nc\n", b'\n'), expected);
+        assert!(paths(b"", b'\n').is_empty());
+        assert!(paths(b"\n\n", b'\n').is_empty());
+    }
+
+    #[test]
+    fn read_paths_by_nul() {
+        assert_eq!(
+            paths(b"line\nbreak\0\0c\0", 0),
+            [PathBuf::from("line\nbreak"), PathBuf::from("c")]
+        );
+        // not UTF-8
+        assert_eq!(
+            paths(b"\xff\0", 0),
+            [PathBuf::from(OsString::from_vec(vec![0xff]))]
+        );
+    }
 }
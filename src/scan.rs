@@ -0,0 +1,1289 @@
+use std::{
+    cell::RefCell,
+    cmp::{self, Reverse},
+    collections::{hash_map::Entry, BinaryHeap, HashMap, HashSet},
+    fmt,
+    fs::{self, File},
+    io, mem,
+    os::unix::fs::MetadataExt,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use anyhow::{bail, Context};
+use rayon::iter::{IntoParallelIterator, ParallelBridge, ParallelIterator};
+
+use crate::{
+    bloom::{splitmix64, BloomFilter},
+    btrfs::{self, TreeSearch},
+    extent_size_bucket, fragmentation_bucket, generation_age_bucket, index, ratio_bucket,
+    CompressionType, CompvizError, Statistic,
+};
+
+pub type FileFn = dyn Fn(&Path, &Statistic) + Send + Sync;
+/// Called with the statistic of every regular file once its extents are enumerated,
+/// for custom per-file processing next to the aggregated [Statistic].
+///
+/// It's called from the rayon worker threads, concurrently and in no particular order,
+/// hence `Fn + Send + Sync` rather than `FnMut`. Keep it short, the calling thread doesn't scan
+/// meanwhile. State to accumulate into goes behind a lock, or the statistics are sent
+/// through a channel to be processed elsewhere:
+///
+/// ```no_run
+/// use std::{path::PathBuf, sync::mpsc, thread};
+///
+/// use compviz::{scan, AnalyzeOptions, FileCallback};
+///
+/// let (tx, rx) = mpsc::channel::<(PathBuf, usize)>();
+/// let consumer = thread::spawn(move || {
+///     for (path, disk_bytes) in rx {
+///         println!("{}: {disk_bytes}", path.display());
+///     }
+/// });
+/// let opts = AnalyzeOptions {
+///     on_file: Some(FileCallback::new(move |path, stat| {
+///         let _ = tx.send((path.to_owned(), stat.total().disk_bytes));
+///     })),
+///     ..Default::default()
+/// };
+/// // the callback and with it the sender is dropped with opts, ending the consumer
+/// scan(&["/mnt/data".into()], &opts)?;
+/// drop(opts);
+/// consumer.join().unwrap();
+/// # Ok::<(), anyhow::Error>(())
+/// ```
+///
+/// A hardlinked file is passed once, like it's counted once. The statistic is that of the
+/// file alone: disk bytes of extents shared with a file passed earlier are left out unless
+/// [AnalyzeOptions::no_dedup], and [Statistic::exclusive_bytes]/[Statistic::shared_bytes]
+/// are 0. With [AnalyzeOptions::types] files without any extent of those types are left out.
+#[derive(Clone)]
+pub struct FileCallback(pub Arc<FileFn>);
+impl FileCallback {
+    pub fn new(f: impl Fn(&Path, &Statistic) + Send + Sync + 'static) -> Self {
+        Self(Arc::new(f))
+    }
+}
+impl fmt::Debug for FileCallback {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("FileCallback")
+    }
+}
+
+/// Options of a single [scan] or [analyze].
+#[derive(Debug, Clone, Default)]
+pub struct AnalyzeOptions {
+    /// Size of the rayon thread pool used for the scan, see [default_num_threads] if unset.
+    pub threads: Option<usize>,
+    /// Size in bytes of the tree search buffer each thread allocates, 64KiB if unset.
+    pub buf_size: Option<usize>,
+    /// Scan even if the path doesn't look like btrfs.
+    pub force: bool,
+    /// Collect [Statistic::generation_age].
+    pub generation_histogram: bool,
+    /// Collect the per-file statistics for an [index::Index].
+    pub dump_index: bool,
+    /// Skip everything whose `st_dev` differs from that of the path it was found under.
+    pub one_file_system: bool,
+    /// Only count files on the filesystems mounted at these paths, by their `st_dev`.
+    ///
+    /// Directories on the device of the path they were found under are still descended into,
+    /// so mounts below it are reached, but only files on the listed devices are counted.
+    /// Other mounts aren't descended into.
+    pub follow_mounts: Vec<PathBuf>,
+    /// See [FileCallback].
+    pub on_file: Option<FileCallback>,
+    /// Pass paths to [Self::on_file], and collect them for [ScanResult::top] and
+    /// [ScanResult::defrag_candidates], relative to the given path they were found under.
+    /// A given path that is a file itself is passed by its file name.
+    pub relative: bool,
+    /// Count the disk bytes of every reference to an extent, not only of the first one.
+    ///
+    /// Sums then add up per file regardless of reflinks, but exceed the physical usage
+    /// wherever files or snapshots share extents.
+    pub no_dedup: bool,
+    /// Deduplicate extents with a bloom filter of bounded memory rather than exactly.
+    pub approx_dedup: Option<ApproxDedup>,
+    /// Leave preallocated extents out of the byte counts, they're only counted in
+    /// [Statistic::n_prealloc] then.
+    pub no_prealloc: bool,
+    /// Bucket extents by their size into [Statistic::extent_sizes].
+    pub extent_size_histogram: bool,
+    /// Bucket files by their compression ratio into [Statistic::ratio_files].
+    pub ratio_histogram: bool,
+    /// Bucket files by their number of extents into [Statistic::fragmentation].
+    pub fragmentation: bool,
+    /// Also aggregate the statistic per group of files into [ScanResult::groups].
+    pub group_by: Option<GroupBy>,
+    /// Collect this many regular files using the most disk into [ScanResult::top].
+    pub top: Option<usize>,
+    /// Collect the regular files that are both fragmented and poorly compressed
+    /// into [ScanResult::defrag_candidates].
+    pub defrag_candidates: Option<DefragThresholds>,
+    /// Collect the paths of skipped non-regular files into [ScanResult::skipped].
+    pub list_skipped: bool,
+    /// Don't descend more than this many levels below the given paths, which are at depth 0.
+    pub max_depth: Option<usize>,
+    /// Follow symlinks found below the given paths, each directory is still walked only once.
+    ///
+    /// | symlink            | given path | found below            |
+    /// |--------------------|------------|------------------------|
+    /// | to a file or dir   | followed   | followed only if set   |
+    /// | dangling           | an error   | skipped                |
+    ///
+    /// Followed links are scanned as their target, so [Self::one_file_system] compares
+    /// the device of the target, and a given symlink's target is the root device.
+    pub follow_symlinks: bool,
+    /// Snapshots of the given paths, scanned along with them. Extents are then also told apart
+    /// by whether they're referenced from the given paths, the snapshots or both,
+    /// see [Statistic::snapshot_only_bytes].
+    pub snapshots: Vec<PathBuf>,
+    /// Entries below the given paths to skip, see [is_excluded].
+    pub exclude: Vec<glob::Pattern>,
+    /// Only walk the tree and count the regular files in [Statistic::n_files],
+    /// without reading their extents. See [count_files].
+    pub count_only: bool,
+    /// Incremented for every regular file scanned, e.g. to show progress from another thread.
+    pub progress: Option<Arc<AtomicUsize>>,
+    /// Only extents written in this generation (transid) or later are accumulated.
+    pub since_generation: Option<u64>,
+    /// Only extents of these compression types are accumulated, all of them if empty.
+    pub types: Vec<CompressionType>,
+    /// Only regular files at least this many bytes long are scanned.
+    pub min_size: Option<u64>,
+    /// Only regular files at most this many bytes long are scanned.
+    pub max_size: Option<u64>,
+}
+/// How [AnalyzeOptions::approx_dedup] remembers the extents seen.
+///
+/// Exact deduplication takes some 20 bytes per extent, which adds up to gigabytes with
+/// hundreds of millions of extents. The filter takes `memory` bytes however many there are,
+/// and holds up to `8 * memory * ln(2)^2 / -ln(fp_rate)` extents, about 1.2 bytes per extent
+/// at 1%, before more than `fp_rate` of the extents are missed. A missed extent is taken
+/// for one seen before, so the disk bytes and extent counts come out that much too low.
+///
+/// Which extents are shared by several files isn't tracked then.
+#[derive(Debug, Clone, Copy)]
+pub struct ApproxDedup {
+    pub memory: usize,
+    pub fp_rate: f64,
+}
+
+/// State shared by the enumerators of all threads of a [scan].
+#[derive(Debug, Default)]
+struct SharedState {
+    /// `disk_bytenr`s of the extents counted so far, with whether several files share them.
+    pub seen_extents: ExtentSet,
+    /// `(st_dev, st_ino)` of the files counted so far, so hardlinks are only counted once.
+    /// The device is part of the key as inode numbers restart in each subvolume.
+    pub seen_inodes: Mutex<HashSet<(u64, u64)>>,
+    /// `(st_dev, st_ino)` of the directories walked so far, only tracked when following symlinks.
+    pub seen_dirs: Mutex<HashSet<(u64, u64)>>,
+    /// The largest files so far by disk usage, the smallest of them on top.
+    pub top: Mutex<BinaryHeap<Reverse<TopFile>>>,
+    /// `st_dev`s of [AnalyzeOptions::follow_mounts], fixed before the scan starts.
+    pub mount_devs: HashSet<u64>,
+    /// Non-regular files skipped so far, only collected if [AnalyzeOptions::list_skipped].
+    pub skipped: Mutex<Vec<PathBuf>>,
+    /// Files found so far exceeding [AnalyzeOptions::defrag_candidates].
+    pub defrag_candidates: Mutex<Vec<DefragCandidate>>,
+    /// Everything that failed so far.
+    pub errors: Mutex<Vec<ScanError>>,
+}
+impl SharedState {
+    /// Whether inode `ino` of the subvolume with `st_dev` `dev` is found for the first time,
+    /// false for any further hardlink to it.
+    fn first_link(&self, dev: u64, ino: u64) -> bool {
+        self.seen_inodes.lock().unwrap().insert((dev, ino))
+    }
+    fn report(&self, path: &Path, error: anyhow::Error) {
+        tracing::warn!(path = %path.display(), "{error:#}");
+        self.errors.lock().unwrap().push(ScanError {
+            path: path.to_owned(),
+            error,
+        });
+    }
+}
+/// The `disk_bytenr`s seen so far along with the first file referencing each,
+/// split over several locks so threads inserting different extents rarely wait for each other.
+///
+/// With a bloom filter only that remembers them.
+#[derive(Debug)]
+struct ExtentSet {
+    shards: [Mutex<HashMap<u64, ExtentOwner>>; Self::SHARDS],
+    approx: Option<BloomFilter>,
+}
+/// The first file referencing an extent and who else does, packed into 8 bytes
+/// as there's one for every extent of the scan.
+#[derive(Debug, Clone, Copy)]
+struct ExtentOwner {
+    /// Hash of `(st_dev, st_ino)` of the first file referencing the extent, see [Self::file].
+    file: u32,
+    /// The disk bytes in 4KiB sectors above the [Self::FLAGS] bits.
+    bits: u32,
+}
+impl ExtentOwner {
+    /// Whether another file references the extent too.
+    const SHARED: u32 = 1;
+    /// Whether it's referenced from below the given paths.
+    const CURRENT: u32 = 1 << 1;
+    /// Whether it's referenced from below the snapshots.
+    const SNAPSHOT: u32 = 1 << 2;
+    const FLAGS: u32 = 3;
+    const SECTOR_SHIFT: u32 = 12;
+    fn new(file: u32, disk_bytes: u64, snapshot: bool) -> Self {
+        // extents are sector aligned and at most 128MiB, far below what the bits can hold
+        let sectors = disk_bytes
+            .div_ceil(1 << Self::SECTOR_SHIFT)
+            .min((u32::MAX >> Self::FLAGS) as u64) as u32;
+        let mut owner = Self {
+            file,
+            bits: sectors << Self::FLAGS,
+        };
+        owner.set(if snapshot {
+            Self::SNAPSHOT
+        } else {
+            Self::CURRENT
+        });
+        owner
+    }
+    /// The hash a file is remembered by. Should two files referencing the same extent
+    /// collide in its 32 bits, the extent is taken to be exclusive to the first of them.
+    fn file((dev, ino): (u64, u64)) -> u32 {
+        splitmix64(splitmix64(dev) ^ ino) as u32
+    }
+    fn disk_bytes(&self) -> u64 {
+        u64::from(self.bits >> Self::FLAGS) << Self::SECTOR_SHIFT
+    }
+    fn is(&self, flag: u32) -> bool {
+        self.bits & flag != 0
+    }
+    fn set(&mut self, flag: u32) {
+        self.bits |= flag;
+    }
+}
+/// Disk bytes of the extents of an [ExtentSet] by who references them.
+#[derive(Debug, Default)]
+struct Sharing {
+    exclusive: usize,
+    shared: usize,
+    /// Number of the extents behind `shared`.
+    n_shared: usize,
+    current_only: usize,
+    snapshot_shared: usize,
+    snapshot_only: usize,
+}
+impl ExtentSet {
+    const SHARDS: usize = 64;
+    fn new(approx: Option<ApproxDedup>) -> Self {
+        Self {
+            approx: approx.map(|a| BloomFilter::new(a.memory, a.fp_rate)),
+            ..Default::default()
+        }
+    }
+    /// Insert `bytenr` referenced by `file`, found below a snapshot or not,
+    /// returning whether it wasn't seen before.
+    fn insert(&self, bytenr: u64, file: (u64, u64), disk_bytes: u64, snapshot: bool) -> bool {
+        if let Some(approx) = &self.approx {
+            return approx.insert(bytenr);
+        }
+        let file = ExtentOwner::file(file);
+        // extents are sector aligned, so the lowest bits are always zero
+        let shard = (bytenr >> 12) as usize % Self::SHARDS;
+        match self.shards[shard].lock().unwrap().entry(bytenr) {
+            Entry::Occupied(mut e) => {
+                let owner = e.get_mut();
+                if owner.file != file {
+                    owner.set(ExtentOwner::SHARED);
+                }
+                owner.set(if snapshot {
+                    ExtentOwner::SNAPSHOT
+                } else {
+                    ExtentOwner::CURRENT
+                });
+                false
+            }
+            Entry::Vacant(e) => {
+                e.insert(ExtentOwner::new(file, disk_bytes, snapshot));
+                true
+            }
+        }
+    }
+    /// The number of extents remembered, unknown for a bloom filter, and roughly the bytes taken.
+    ///
+    /// Nothing is ever removed, so that's the peak of the scan too.
+    fn memory(&self) -> (Option<usize>, usize) {
+        if let Some(approx) = &self.approx {
+            return (None, approx.memory());
+        }
+        let (mut len, mut bytes) = (0, 0);
+        for shard in &self.shards {
+            let shard = shard.lock().unwrap();
+            len += shard.len();
+            // a hashbrown bucket is the entry and a control byte
+            bytes += shard.capacity() * (size_of::<(u64, ExtentOwner)>() + 1);
+        }
+        (Some(len), bytes)
+    }
+    /// Disk bytes of the extents referenced by a single file and by several files,
+    /// and by the given paths and their snapshots.
+    fn sharing(&self) -> Sharing {
+        let mut sharing = Sharing::default();
+        for shard in &self.shards {
+            for owner in shard.lock().unwrap().values() {
+                let disk_bytes = owner.disk_bytes() as usize;
+                if owner.is(ExtentOwner::SHARED) {
+                    sharing.shared += disk_bytes;
+                    sharing.n_shared += 1;
+                } else {
+                    sharing.exclusive += disk_bytes;
+                }
+                match (
+                    owner.is(ExtentOwner::CURRENT),
+                    owner.is(ExtentOwner::SNAPSHOT),
+                ) {
+                    (true, true) => sharing.snapshot_shared += disk_bytes,
+                    (false, true) => sharing.snapshot_only += disk_bytes,
+                    _ => sharing.current_only += disk_bytes,
+                }
+            }
+        }
+        sharing
+    }
+}
+impl Default for ExtentSet {
+    fn default() -> Self {
+        Self {
+            shards: std::array::from_fn(|_| Mutex::default()),
+            approx: None,
+        }
+    }
+}
+struct FileExtentsEnumerator {
+    args: btrfs::SearchArgs,
+    shared: Arc<SharedState>,
+    /// Current generation of each filesystem seen so far by `st_dev`,
+    /// if extents are to be bucketed by age.
+    fs_generations: Option<HashMap<u64, u64>>,
+    stat: Statistic,
+    /// Per-file statistics, collected only for `--dump-index`.
+    index: Option<Vec<index::IndexEntry>>,
+    top: Option<usize>,
+    /// Per-group statistics, collected only if grouping.
+    groups: Option<(GroupBy, HashMap<String, Statistic>)>,
+    ratio_histogram: bool,
+    extent_size_histogram: bool,
+    fragmentation: bool,
+    defrag_candidates: Option<DefragThresholds>,
+    relative: bool,
+    no_dedup: bool,
+    no_prealloc: bool,
+    one_file_system: bool,
+    on_file: Option<FileCallback>,
+    types: Vec<CompressionType>,
+    since_generation: Option<u64>,
+    snapshots: Vec<PathBuf>,
+}
+impl FileExtentsEnumerator {
+    pub fn with_shared(shared: Arc<SharedState>, opts: &AnalyzeOptions) -> Self {
+        Self {
+            args: btrfs::SearchArgs::new_search_file_extent_data(
+                0,
+                opts.since_generation.unwrap_or(0),
+                opts.buf_size.unwrap_or(btrfs::SearchArgs::DEFAULT_BUF_SIZE),
+            ),
+            stat: Statistic::default(),
+            shared,
+            fs_generations: opts.generation_histogram.then(HashMap::new),
+            index: opts.dump_index.then(Vec::new),
+            top: opts.top,
+            groups: opts.group_by.map(|by| (by, HashMap::new())),
+            ratio_histogram: opts.ratio_histogram,
+            extent_size_histogram: opts.extent_size_histogram,
+            fragmentation: opts.fragmentation,
+            defrag_candidates: opts.defrag_candidates,
+            relative: opts.relative,
+            no_dedup: opts.no_dedup,
+            no_prealloc: opts.no_prealloc,
+            one_file_system: opts.one_file_system,
+            on_file: opts.on_file.clone(),
+            types: opts.types.clone(),
+            since_generation: opts.since_generation,
+            snapshots: opts.snapshots.clone(),
+        }
+    }
+    /// Enumerate the extents of the regular file at `path` into `stat`, returning its inode number,
+    /// or `None` if the file is a hardlink to an inode counted already or is skipped otherwise.
+    fn stat_file(
+        &mut self,
+        path: &Path,
+        root_dev: u64,
+        snapshot: bool,
+        stat: &mut Statistic,
+    ) -> anyhow::Result<Option<u64>> {
+        let f = File::open(path)?;
+        let metadata = f.metadata()?;
+        let ino = metadata.ino();
+        // a file bind mounted from elsewhere
+        if self.one_file_system && metadata.dev() != root_dev {
+            return Ok(None);
+        }
+        let mount_devs = &self.shared.mount_devs;
+        if !mount_devs.is_empty() && !mount_devs.contains(&metadata.dev()) {
+            return Ok(None);
+        }
+        if !self.shared.first_link(metadata.dev(), ino) {
+            return Ok(None);
+        }
+        let fs_generation = self.fs_generation(&f, metadata.dev())?;
+        self.stat_extents(f, fs_generation, metadata.dev(), ino, snapshot, stat)?;
+        Ok(Some(ino))
+    }
+    /// The generation of the filesystem `f` is on, `dev` being its `st_dev`,
+    /// or `None` without `--generation-histogram`.
+    fn fs_generation(&mut self, f: &File, dev: u64) -> Result<Option<u64>, CompvizError> {
+        Ok(match &mut self.fs_generations {
+            Some(generations) => Some(match generations.entry(dev) {
+                Entry::Occupied(e) => *e.get(),
+                Entry::Vacant(e) => *e.insert(btrfs::get_fs_generation(f)?),
+            }),
+            None => None,
+        })
+    }
+    /// Enumerate the extents of inode `ino` found by `searcher`, usually a file of the
+    /// subvolume, into `stat`, `dev` being the `st_dev` of that subvolume.
+    fn stat_extents(
+        &mut self,
+        searcher: impl TreeSearch,
+        fs_generation: Option<u64>,
+        dev: u64,
+        ino: u64,
+        snapshot: bool,
+        stat: &mut Statistic,
+    ) -> anyhow::Result<()> {
+        stat.n_files += 1;
+        self.args
+            .set_search_file_extent_data(ino, self.since_generation.unwrap_or(0));
+        let mut iter = btrfs::get_file_extents_with(searcher, &mut self.args)?;
+        for extent in iter.into_iter() {
+            let extent = extent?;
+            // A regular extent pointing at disk_bytenr 0 is a hole, it occupies no disk space.
+            if extent.disk_bytenr() == Some(0) {
+                stat.n_holes += 1;
+                stat.hole_bytes += extent.num_bytes() as usize;
+                continue;
+            }
+            // the search returns all extents in tree blocks written since then, old ones included
+            if self
+                .since_generation
+                .is_some_and(|since| extent.generation() < since)
+            {
+                if extent.type_() == btrfs::BtrfsFileExtentType::Inline {
+                    break;
+                }
+                continue;
+            }
+            let compression = CompressionType::from(extent.compression());
+            if !self.types.is_empty() && !self.types.contains(&compression) {
+                if extent.type_() == btrfs::BtrfsFileExtentType::Inline {
+                    break;
+                }
+                continue;
+            }
+            let encrypted = extent.encryption() != 0;
+            if encrypted {
+                stat.n_encrypted += 1;
+            }
+            let prealloc = extent.type_() == btrfs::BtrfsFileExtentType::Prealloc;
+            if prealloc {
+                stat.n_prealloc += 1;
+                if self.no_prealloc {
+                    continue;
+                }
+            }
+            let info = stat.extent_info.entry(compression).or_default();
+            let age_bucket = fs_generation.map(|g| {
+                &mut stat.generation_age
+                    [generation_age_bucket(g.saturating_sub(extent.generation()))]
+            });
+            if extent.type_() == btrfs::BtrfsFileExtentType::Inline {
+                info.disk_bytes += extent.disk_num_bytes() as usize;
+                info.uncompressed_bytes += extent.ram_bytes() as usize;
+                info.referenced_bytes += extent.ram_bytes() as usize;
+                if let Some(age_bucket) = age_bucket {
+                    *age_bucket += extent.disk_num_bytes() as usize;
+                }
+                stat.inline.disk_bytes += extent.disk_num_bytes() as usize;
+                stat.inline.uncompressed_bytes += extent.ram_bytes() as usize;
+                stat.inline.referenced_bytes += extent.ram_bytes() as usize;
+                if encrypted {
+                    stat.encrypted_bytes += extent.disk_num_bytes() as usize;
+                }
+                stat.n_inline += 1;
+                break;
+            }
+            // okay to unwrap as only INLINE extents will have a None, and we break early
+            let first = self.shared.seen_extents.insert(
+                extent.disk_bytenr().unwrap(),
+                (dev, ino),
+                extent.disk_num_bytes(),
+                snapshot,
+            );
+            if first || self.no_dedup {
+                info.disk_bytes += extent.disk_num_bytes() as usize;
+                info.uncompressed_bytes += extent.ram_bytes() as usize;
+                if let Some(age_bucket) = age_bucket {
+                    *age_bucket += extent.disk_num_bytes() as usize;
+                }
+                if encrypted {
+                    stat.encrypted_bytes += extent.disk_num_bytes() as usize;
+                }
+                if self.extent_size_histogram {
+                    stat.extent_sizes[extent_size_bucket(extent.disk_num_bytes())] += 1;
+                }
+                if prealloc {
+                    stat.prealloc.disk_bytes += extent.disk_num_bytes() as usize;
+                    stat.prealloc.uncompressed_bytes += extent.ram_bytes() as usize;
+                    stat.prealloc.n_extents += 1;
+                }
+                info.n_extents += 1;
+                stat.n_extents += 1;
+            }
+            info.referenced_bytes += extent.num_bytes() as usize;
+            info.n_refs += 1;
+            if prealloc {
+                stat.prealloc.referenced_bytes += extent.num_bytes() as usize;
+                stat.prealloc.n_refs += 1;
+            }
+            stat.n_refs += 1;
+        }
+        Ok(())
+    }
+    /// Enumerate the regular file at `path` into the statistic of this thread.
+    ///
+    /// `root` is the top-level path `path` was found under, `root_dev` its `st_dev`.
+    pub fn work_on_file(&mut self, path: &Path, root: &Path, root_dev: u64) -> anyhow::Result<()> {
+        tracing::trace!(path = %path.display(), "enumerating extents");
+        let mut file_stat = Statistic::default();
+        // fold whatever was read even on errors, as its extents are marked seen already
+        let snapshot = self.snapshots.iter().any(|s| s == root);
+        let ino = self.stat_file(path, root_dev, snapshot, &mut file_stat);
+        if self.ratio_histogram && matches!(ino, Ok(Some(_))) {
+            let total = file_stat.total();
+            let bucket = ratio_bucket(&total);
+            file_stat.ratio_files[bucket] += 1;
+            file_stat.ratio_bytes[bucket] += total.disk_bytes;
+        }
+        if self.fragmentation && matches!(ino, Ok(Some(_))) {
+            file_stat.fragmentation[fragmentation_bucket(file_stat.n_refs + file_stat.n_inline)] +=
+                1;
+        }
+        self.stat += &file_stat;
+        let Some(ino) = ino? else {
+            return Ok(());
+        };
+        let shown = if self.relative {
+            match path.strip_prefix(root) {
+                Ok(relative) if relative.as_os_str().is_empty() => {
+                    path.file_name().map_or(path, Path::new)
+                }
+                Ok(relative) => relative,
+                Err(_) => path,
+            }
+        } else {
+            path
+        };
+        if let Some(thresholds) = self.defrag_candidates {
+            let total = file_stat.total();
+            let n_extents = file_stat.n_refs + file_stat.n_inline;
+            // without disk usage of its own, e.g. all reflinked, there's no ratio to speak of
+            if total.disk_bytes > 0
+                && n_extents > thresholds.min_extents
+                && total.ratio() < thresholds.max_ratio
+            {
+                self.shared
+                    .defrag_candidates
+                    .lock()
+                    .unwrap()
+                    .push(DefragCandidate {
+                        path: shown.to_owned(),
+                        n_extents,
+                        ratio: total.ratio(),
+                    });
+            }
+        }
+        if let Some(on_file) = &self.on_file {
+            // with a type filter, files without any extent of those types aren't interesting
+            if self.types.is_empty() || !file_stat.extent_info.is_empty() {
+                (on_file.0)(shown, &file_stat);
+            }
+        }
+        if let Some(n) = self.top {
+            let disk_bytes = file_stat.total().disk_bytes;
+            let mut top = self.shared.top.lock().unwrap();
+            // a full heap is only touched by files larger than its smallest one
+            if top.len() < n
+                || top
+                    .peek()
+                    .is_some_and(|Reverse(min)| min.disk_bytes < disk_bytes)
+            {
+                top.push(Reverse(TopFile {
+                    disk_bytes,
+                    path: shown.to_owned(),
+                    stat: file_stat.clone(),
+                }));
+                if top.len() > n {
+                    top.pop();
+                }
+            }
+        }
+        if let Some((by, groups)) = &mut self.groups {
+            *groups.entry(by.key(path, root)).or_default() += &file_stat;
+        }
+        if let Some(index) = &mut self.index {
+            index.push(index::IndexEntry::new(ino, path, file_stat));
+        }
+        Ok(())
+    }
+}
+/// Whether a file of `len` bytes is within `--min-size` and `--max-size`, both inclusive.
+fn size_allowed(len: u64, min: Option<u64>, max: Option<u64>) -> bool {
+    min.is_none_or(|min| len >= min) && max.is_none_or(|max| len <= max)
+}
+/// Scan `path` on the current rayon pool, recursing into directories with their entries
+/// spread over the pool in chunks.
+///
+/// The thread local enumerator is only borrowed while a regular file is enumerated,
+/// so a thread waiting for the entries of a directory can work on others meanwhile.
+/// Errors below `path` are reported as they happen, only an unreadable `path` itself is returned.
+/// So an unreadable directory, or an entry that fails to list, is skipped on its own
+/// while its siblings are still walked.
+///
+/// `depth` is how many levels `path` is below `root`.
+fn walk(
+    shared: &SharedState,
+    opts: &AnalyzeOptions,
+    path: &Path,
+    file_type: fs::FileType,
+    root: &Path,
+    root_dev: u64,
+    depth: usize,
+) -> anyhow::Result<()> {
+    // leave whatever is left of the tree, the pool then winds down with what was counted
+    if INTERRUPTED.load(Ordering::Relaxed) {
+        return Ok(());
+    }
+    if file_type.is_file() {
+        // checked before opening the file, so it costs an extra stat only if filtered by size
+        if (opts.min_size.is_some() || opts.max_size.is_some())
+            && !size_allowed(fs::metadata(path)?.len(), opts.min_size, opts.max_size)
+        {
+            return Ok(());
+        }
+        if opts.count_only {
+            T_ENUMRATOR.with_borrow_mut(|e| e.stat.n_files += 1);
+            return Ok(());
+        }
+        let result = T_ENUMRATOR.with_borrow_mut(|e| e.work_on_file(path, root, root_dev));
+        if let Some(progress) = &opts.progress {
+            progress.fetch_add(1, Ordering::Relaxed);
+        }
+        return result;
+    }
+    if !file_type.is_dir() {
+        // symlinks not followed aren't files of their own
+        if !file_type.is_symlink() {
+            T_ENUMRATOR.with_borrow_mut(|e| e.stat.n_special += 1);
+            if opts.list_skipped {
+                shared.skipped.lock().unwrap().push(path.to_owned());
+            }
+        }
+        return Ok(());
+    }
+    // the entries would be deeper than allowed
+    if opts.max_depth.is_some_and(|max| depth >= max) {
+        return Ok(());
+    }
+    // following symlinks may lead back into a directory being walked, or into one walked already
+    if opts.follow_symlinks {
+        let metadata = fs::metadata(path)?;
+        if !shared
+            .seen_dirs
+            .lock()
+            .unwrap()
+            .insert((metadata.dev(), metadata.ino()))
+        {
+            return Ok(());
+        }
+    }
+    let span = tracing::debug_span!("walk", path = %path.display());
+    let _enter = span.enter();
+    fs::read_dir(path)?.par_bridge().for_each(|entry| {
+        // the entries may be walked on other threads, keep them nested under this directory
+        let _enter = span.enter();
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(err) => return shared.report(path, err.into()),
+        };
+        let path = entry.path();
+        if is_excluded(&opts.exclude, &path) {
+            return;
+        }
+        let result = entry
+            .file_type()
+            .map_err(anyhow::Error::from)
+            .and_then(|mut file_type| {
+                let mut target = None;
+                if file_type.is_symlink() {
+                    if !opts.follow_symlinks {
+                        return Ok(());
+                    }
+                    let metadata = match fs::metadata(&path) {
+                        Ok(metadata) => metadata,
+                        // dangling, there's nothing to count
+                        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(()),
+                        Err(err) => return Err(err.into()),
+                    };
+                    file_type = metadata.file_type();
+                    target = Some(metadata);
+                }
+                // a mount point, files don't need this as they are checked once opened
+                let mount_devs = &shared.mount_devs;
+                if (opts.one_file_system || !mount_devs.is_empty()) && file_type.is_dir() {
+                    let dev = match &target {
+                        Some(metadata) => metadata.dev(),
+                        None => entry.metadata()?.dev(),
+                    };
+                    if dev != root_dev && !mount_devs.contains(&dev) {
+                        return Ok(());
+                    }
+                }
+                walk(shared, opts, &path, file_type, root, root_dev, depth + 1)
+            });
+        match result {
+            Ok(()) => {}
+            // deleted or renamed after it was listed, as happens in any directory in use
+            Err(err) if io_error_kind(&err) == Some(io::ErrorKind::NotFound) => {
+                tracing::debug!(path = %path.display(), "vanished during the scan");
+                T_ENUMRATOR.with_borrow_mut(|e| e.stat.n_vanished += 1);
+            }
+            Err(err) => shared.report(&path, err),
+        }
+    });
+    Ok(())
+}
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+/// Make SIGINT stop a running [scan] early instead of killing the process,
+/// the scan then returns what was counted so far with [ScanResult::interrupted] set.
+///
+/// A second SIGINT kills the process as usual.
+pub fn handle_interrupts() -> io::Result<()> {
+    extern "C" fn on_sigint(_: libc::c_int) {
+        INTERRUPTED.store(true, Ordering::Relaxed);
+        unsafe {
+            // SAFETY: signal is async-signal-safe
+            libc::signal(libc::SIGINT, libc::SIG_DFL);
+        }
+    }
+    let ret = unsafe {
+        // SAFETY: the handler only touches an atomic and calls async-signal-safe functions
+        libc::signal(
+            libc::SIGINT,
+            on_sigint as extern "C" fn(libc::c_int) as libc::sighandler_t,
+        )
+    };
+    if ret == libc::SIG_ERR {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+/// Whether SIGINT arrived since [handle_interrupts] was called.
+pub fn interrupted() -> bool {
+    INTERRUPTED.load(Ordering::Relaxed)
+}
+/// Whether `path` matches any of `exclude`.
+///
+/// Patterns containing a `/` are matched against the whole path,
+/// others only against the file name, so `node_modules` skips it at any depth.
+pub fn is_excluded(exclude: &[glob::Pattern], path: &Path) -> bool {
+    exclude.iter().any(|pattern| {
+        if pattern.as_str().contains('/') {
+            pattern.matches_path(path)
+        } else {
+            path.file_name()
+                .and_then(|name| name.to_str())
+                .is_some_and(|name| pattern.matches(name))
+        }
+    })
+}
+thread_local! {
+    static T_ENUMRATOR: RefCell<FileExtentsEnumerator> = panic!("thread local enumrator not initialized");
+}
+/// The thread count that benchmarked best, based on the number of logical CPUs.
+pub fn default_num_threads() -> usize {
+    let cpus = std::thread::available_parallelism()
+        .map(|x| x.get())
+        .unwrap_or(1);
+    match cpus {
+        0..=6 => cpus,
+        24..usize::MAX => 24,
+        _ => cpus / 2 + 1,
+    }
+}
+/// Everything a [scan] produces.
+#[derive(Debug, Default)]
+pub struct ScanResult {
+    pub stat: Statistic,
+    /// Per-file statistics, only if [AnalyzeOptions::dump_index] is set.
+    pub index: Vec<index::IndexEntry>,
+    /// Number of the given paths, snapshots included.
+    pub n_roots: usize,
+    /// Number of the given paths, snapshots included, that couldn't be scanned at all.
+    pub failed_roots: usize,
+    /// Every file or directory that couldn't be read, including the failed roots.
+    pub errors: Vec<ScanError>,
+    /// Sockets, FIFOs and device nodes that were skipped, if [AnalyzeOptions::list_skipped].
+    pub skipped: Vec<PathBuf>,
+    /// The files using the most disk, largest first, if [AnalyzeOptions::top] is set.
+    pub top: Vec<TopFile>,
+    /// Fragmented and poorly compressed files, most extents first,
+    /// if [AnalyzeOptions::defrag_candidates] is set.
+    pub defrag_candidates: Vec<DefragCandidate>,
+    /// Statistic of each group, if [AnalyzeOptions::group_by] is set.
+    pub groups: HashMap<String, Statistic>,
+    /// Whether the scan was stopped early by SIGINT, see [handle_interrupts].
+    pub interrupted: bool,
+    /// Number of unique extents remembered for deduplication,
+    /// `None` with [AnalyzeOptions::approx_dedup] as a bloom filter doesn't know.
+    pub dedup_extents: Option<usize>,
+    /// Approximate bytes taken by remembering the extents, as of the end of the scan.
+    pub dedup_bytes: usize,
+}
+impl ScanResult {
+    /// Whether none of the given paths and snapshots could be scanned, so there's nothing to report.
+    pub fn all_failed(&self) -> bool {
+        self.failed_roots == self.n_roots
+    }
+}
+/// How files are grouped for [AnalyzeOptions::group_by].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GroupBy {
+    /// By lowercased file extension, `none` for files without one.
+    Extension,
+    /// By the directory directly below the given path the file was found under,
+    /// files directly in that path are grouped as the path itself.
+    TopDir,
+}
+impl GroupBy {
+    /// The group the file at `path` found under the given path `root` belongs to.
+    pub fn key(&self, path: &Path, root: &Path) -> String {
+        match self {
+            GroupBy::Extension => path.extension().map_or_else(
+                || "none".to_string(),
+                |e| e.to_string_lossy().to_lowercase(),
+            ),
+            GroupBy::TopDir => {
+                let relative = path.strip_prefix(root).unwrap_or(path);
+                let mut components = relative.components();
+                match (components.next(), components.next()) {
+                    (Some(dir), Some(_)) => root.join(dir).display().to_string(),
+                    _ => root.display().to_string(),
+                }
+            }
+        }
+    }
+}
+/// Which files [AnalyzeOptions::defrag_candidates] collects.
+#[derive(Debug, Clone, Copy)]
+pub struct DefragThresholds {
+    /// Files need more extent references than this.
+    pub min_extents: usize,
+    /// And a compression ratio below this.
+    pub max_ratio: f64,
+}
+/// A regular file collected for [AnalyzeOptions::defrag_candidates].
+#[derive(Debug, Clone)]
+pub struct DefragCandidate {
+    pub path: PathBuf,
+    /// Extent references of the file, inline ones included.
+    pub n_extents: usize,
+    pub ratio: f64,
+}
+/// A regular file collected for [AnalyzeOptions::top], ordered by its disk usage only.
+///
+/// Disk usage of extents shared with other files is attributed to whichever was scanned first.
+#[derive(Debug, Clone)]
+pub struct TopFile {
+    pub disk_bytes: usize,
+    pub path: PathBuf,
+    pub stat: Statistic,
+}
+impl PartialEq for TopFile {
+    fn eq(&self, other: &Self) -> bool {
+        self.disk_bytes == other.disk_bytes
+    }
+}
+impl Eq for TopFile {}
+impl PartialOrd for TopFile {
+    fn partial_cmp(&self, other: &Self) -> Option<cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+impl Ord for TopFile {
+    fn cmp(&self, other: &Self) -> cmp::Ordering {
+        self.disk_bytes.cmp(&other.disk_bytes)
+    }
+}
+/// A file or directory that couldn't be read during a [scan].
+#[derive(Debug)]
+pub struct ScanError {
+    pub path: PathBuf,
+    pub error: anyhow::Error,
+}
+impl ScanError {
+    /// Permission errors are usually not specific to a file but to how compviz is run.
+    pub fn is_permission_denied(&self) -> bool {
+        io_error_kind(&self.error) == Some(io::ErrorKind::PermissionDenied)
+    }
+    /// The error of the btrfs query that failed, `None` if the file or directory
+    /// failed to be read already.
+    pub fn compviz_error(&self) -> Option<&CompvizError> {
+        self.error.downcast_ref()
+    }
+}
+/// The kind of the IO error behind `err`, whether it failed reading the file or directory
+/// or in the btrfs query.
+fn io_error_kind(err: &anyhow::Error) -> Option<io::ErrorKind> {
+    match err.downcast_ref::<CompvizError>() {
+        Some(CompvizError::PermissionDenied(_)) => Some(io::ErrorKind::PermissionDenied),
+        Some(CompvizError::Io(err)) => Some(err.kind()),
+        Some(CompvizError::NotBtrfs) => None,
+        None => err.downcast_ref::<io::Error>().map(io::Error::kind),
+    }
+}
+/// Scan all `paths` with the rayon pool and return the merged statistic of all threads.
+///
+/// Each thread accumulates into its own enumerator, merged once the pool shuts down.
+/// The paths are walked concurrently on the same pool.
+///
+/// Extents are deduplicated across all paths. A path that can't be scanned is collected into
+/// [ScanResult::errors] and counted in [ScanResult::failed_roots], without affecting the others.
+pub fn scan(paths: &[PathBuf], opts: &AnalyzeOptions) -> anyhow::Result<ScanResult> {
+    if let Some(buf_size) = opts.buf_size {
+        let range = btrfs::SearchArgs::MIN_BUF_SIZE..=btrfs::SearchArgs::MAX_BUF_SIZE;
+        if !range.contains(&buf_size) {
+            bail!(
+                "buffer size must be between {} and {} bytes",
+                range.start(),
+                range.end()
+            );
+        }
+    }
+    let mut mount_devs = HashSet::new();
+    for mount in &opts.follow_mounts {
+        let metadata = fs::metadata(mount)
+            .with_context(|| format!("failed to read mount point {}", mount.display()))?;
+        mount_devs.insert(metadata.dev());
+    }
+    let shared = Arc::new(SharedState {
+        seen_extents: ExtentSet::new(opts.approx_dedup),
+        mount_devs,
+        ..Default::default()
+    });
+    let mut failed_roots = 0;
+    let mut roots = Vec::with_capacity(paths.len() + opts.snapshots.len());
+    // many roots, e.g. the files read by --stdin, share a filesystem, so it's checked once each
+    let mut btrfs_devs = HashMap::new();
+    for path in paths.iter().chain(&opts.snapshots) {
+        let root = (|| -> anyhow::Result<fs::Metadata> {
+            let mut metadata = fs::symlink_metadata(path)?;
+            // given paths are followed regardless of follow_symlinks, they're asked for by name
+            if metadata.file_type().is_symlink() {
+                metadata = fs::metadata(path)?;
+            }
+            if !opts.force {
+                let is_btrfs = match btrfs_devs.entry(metadata.dev()) {
+                    Entry::Occupied(e) => *e.get(),
+                    Entry::Vacant(e) => *e.insert(btrfs::is_btrfs(path)?),
+                };
+                if !is_btrfs {
+                    return Err(anyhow::Error::new(CompvizError::NotBtrfs)
+                        .context("not on a btrfs filesystem, pass --force to scan it anyway"));
+                }
+            }
+            Ok(metadata)
+        })();
+        match root {
+            Ok(metadata) => roots.push((path, metadata.file_type(), metadata.dev())),
+            Err(err) => {
+                shared.report(path, err);
+                failed_roots += 1;
+            }
+        }
+    }
+    let stat = Mutex::new(Statistic::default());
+    let index = Mutex::new(Vec::new());
+    let groups = Mutex::new(HashMap::new());
+    rayon::ThreadPoolBuilder::new()
+        .num_threads(opts.threads.unwrap_or_else(default_num_threads))
+        .build_scoped(
+            |thread| {
+                T_ENUMRATOR.set(FileExtentsEnumerator::with_shared(shared.clone(), opts));
+                thread.run();
+                T_ENUMRATOR.with_borrow_mut(|e| {
+                    *stat.lock().unwrap() += &e.stat;
+                    if let Some(entries) = e.index.take() {
+                        index.lock().unwrap().extend(entries);
+                    }
+                    if let Some((_, thread_groups)) = e.groups.take() {
+                        let mut groups = groups.lock().unwrap();
+                        for (key, group) in thread_groups {
+                            *groups.entry(key).or_default() += &group;
+                        }
+                    }
+                });
+            },
+            |pool| {
+                // all roots are walked at once, so a small one doesn't leave workers idle
+                // until the next one starts
+                failed_roots += pool.install(|| {
+                    roots
+                        .into_par_iter()
+                        .filter(|&(path, file_type, dev)| {
+                            match walk(&shared, opts, path, file_type, path, dev, 0) {
+                                Ok(()) => false,
+                                Err(err) => {
+                                    shared.report(path, err);
+                                    true
+                                }
+                            }
+                        })
+                        .count()
+                })
+            },
+        )?;
+    let mut stat = stat.into_inner().unwrap();
+    let sharing = shared.seen_extents.sharing();
+    (stat.exclusive_bytes, stat.shared_bytes) = (sharing.exclusive, sharing.shared);
+    stat.n_shared_extents = sharing.n_shared;
+    if !opts.snapshots.is_empty() {
+        stat.current_only_bytes = sharing.current_only;
+        stat.snapshot_shared_bytes = sharing.snapshot_shared;
+        stat.snapshot_only_bytes = sharing.snapshot_only;
+    }
+    stat.since_generation = opts.since_generation;
+    stat.approx_dedup_fp_rate = shared
+        .seen_extents
+        .approx
+        .as_ref()
+        .map(BloomFilter::false_positive_rate);
+    let (dedup_extents, dedup_bytes) = shared.seen_extents.memory();
+    let errors = mem::take(&mut *shared.errors.lock().unwrap());
+    let skipped = mem::take(&mut *shared.skipped.lock().unwrap());
+    let mut defrag_candidates = mem::take(&mut *shared.defrag_candidates.lock().unwrap());
+    defrag_candidates.sort_by_key(|file| Reverse(file.n_extents));
+    // ascending order of Reverse is descending order of the files
+    let top = mem::take(&mut *shared.top.lock().unwrap())
+        .into_sorted_vec()
+        .into_iter()
+        .map(|Reverse(file)| file)
+        .collect();
+    Ok(ScanResult {
+        stat,
+        index: index.into_inner().unwrap(),
+        n_roots: paths.len() + opts.snapshots.len(),
+        failed_roots,
+        errors,
+        skipped,
+        top,
+        defrag_candidates,
+        groups: groups.into_inner().unwrap(),
+        interrupted: interrupted(),
+        dedup_extents,
+        dedup_bytes,
+    })
+}
+/// Enumerate the extents of inode `ino` in the subvolume of `path`, e.g. its mount point,
+/// into a statistic of its own, for an inode without a path at hand.
+///
+/// The extents are filtered by `opts` as [scan] does, and counted as if the inode were the only
+/// file scanned.
+pub fn stat_inode(path: &Path, ino: u64, opts: &AnalyzeOptions) -> anyhow::Result<Statistic> {
+    if !opts.force && !btrfs::is_btrfs(path)? {
+        return Err(anyhow::Error::new(CompvizError::NotBtrfs)
+            .context("not on a btrfs filesystem, pass --force to scan it anyway"));
+    }
+    let mut f = File::open(path)?;
+    let dev = f.metadata()?.dev();
+    // the inode item itself, so a missing inode isn't mistaken for an empty file
+    let mut args = btrfs::SearchArgs::new_search_inode_items(
+        ino,
+        btrfs::BTRFS_INODE_ITEM_KEY..=btrfs::BTRFS_INODE_ITEM_KEY,
+        0,
+        btrfs::SearchArgs::DEFAULT_BUF_SIZE,
+    );
+    f.search(&mut args)?;
+    if args.key().nr_items == 0 {
+        bail!("no inode {ino} in the subvolume of {}", path.display());
+    }
+    let shared = Arc::new(SharedState {
+        seen_extents: ExtentSet::new(opts.approx_dedup),
+        ..Default::default()
+    });
+    let mut stat = Statistic::default();
+    let mut enumerator = FileExtentsEnumerator::with_shared(shared, opts);
+    let fs_generation = enumerator.fs_generation(&f, dev)?;
+    enumerator.stat_extents(f, fs_generation, dev, ino, false, &mut stat)?;
+    Ok(stat)
+}
+/// Count the regular files [scan] would scan with `opts`, without reading any extents.
+///
+/// Hardlinks are counted for every link, so this is an upper bound meant for progress display.
+pub fn count_files(paths: &[PathBuf], opts: &AnalyzeOptions) -> anyhow::Result<usize> {
+    let opts = AnalyzeOptions {
+        count_only: true,
+        progress: None,
+        on_file: None,
+        // the search buffer is never used
+        buf_size: Some(btrfs::SearchArgs::MIN_BUF_SIZE),
+        ..opts.clone()
+    };
+    Ok(scan(paths, &opts)?.stat.n_files)
+}
+/// Scan all `paths` and return their aggregated statistic.
+///
+/// This is [scan] for callers that only care about the numbers,
+/// it fails only if none of the paths could be scanned.
+pub fn analyze(paths: &[PathBuf], opts: AnalyzeOptions) -> anyhow::Result<Statistic> {
+    let result = scan(paths, &opts)?;
+    if result.all_failed() {
+        bail!("none of the given paths could be scanned");
+    }
+    Ok(result.stat)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::btrfs::fake::{regular, FakeTree, INO};
+
+    #[test]
+    fn hardlinks_are_counted_once() {
+        let dir =
+            std::env::temp_dir().join(format!("compviz-test-hardlinks-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let (a, b) = (dir.join("a"), dir.join("b"));
+        fs::write(&a, [0; 4096]).unwrap();
+        fs::hard_link(&a, &b).unwrap();
+        let root_dev = fs::metadata(&dir).unwrap().dev();
+
+        let mut enumerator =
+            FileExtentsEnumerator::with_shared(Arc::default(), &AnalyzeOptions::default());
+        let mut stat = Statistic::default();
+        // off btrfs the first link fails its search, but is counted all the same
+        let first = enumerator.stat_file(&a, root_dev, false, &mut stat);
+        let second = enumerator.stat_file(&b, root_dev, false, &mut stat);
+        fs::remove_dir_all(&dir).unwrap();
+        assert!(!matches!(first, Ok(None)));
+        assert!(matches!(second, Ok(None)));
+        assert_eq!(stat.n_files, 1);
+    }
+
+    #[test]
+    fn holes_are_counted_apart_from_extents() {
+        let mut hole = regular(4096);
+        hole.extent.disk_bytenr = 0;
+        hole.extent.disk_num_bytes = 0;
+        let mut tree = FakeTree {
+            items: vec![regular(0), hole, regular(8192)],
+            ..Default::default()
+        };
+        let mut enumerator =
+            FileExtentsEnumerator::with_shared(Arc::default(), &AnalyzeOptions::default());
+        let mut stat = Statistic::default();
+        enumerator
+            .stat_extents(&mut tree, None, 1, INO, false, &mut stat)
+            .unwrap();
+        assert_eq!((stat.n_holes, stat.hole_bytes), (1, 4096));
+        assert_eq!((stat.n_extents, stat.n_refs), (2, 2));
+        let none = &stat.extent_info[&CompressionType::None];
+        assert_eq!((none.n_extents, none.n_refs), (2, 2));
+        let total = stat.total();
+        assert_eq!(total.disk_bytes, 8192);
+        assert_eq!(total.referenced_bytes, 8192);
+    }
+
+    #[test]
+    fn size_allowed_bounds_are_inclusive() {
+        let (min, max) = (Some(4096), Some(8192));
+        assert!(size_allowed(4096, min, max));
+        assert!(size_allowed(8192, min, max));
+        assert!(!size_allowed(4095, min, max));
+        assert!(!size_allowed(8193, min, max));
+        assert!(size_allowed(0, None, None));
+        assert!(size_allowed(u64::MAX, min, None));
+    }
+
+    #[test]
+    fn same_inode_in_other_subvolume_counts_again() {
+        let shared = SharedState::default();
+        assert!(shared.first_link(1, 257));
+        // a hardlink within the same subvolume
+        assert!(!shared.first_link(1, 257));
+        // a nested subvolume or snapshot numbers its inodes from 256 again
+        assert!(shared.first_link(2, 257));
+        assert!(!shared.first_link(2, 257));
+    }
+
+    #[test]
+    fn extent_owner_packs_into_8_bytes() {
+        assert_eq!(size_of::<ExtentOwner>(), 8);
+        let owner = ExtentOwner::new(ExtentOwner::file((1, 257)), 128 << 20, true);
+        assert_eq!(owner.disk_bytes(), 128 << 20);
+        assert!(owner.is(ExtentOwner::SNAPSHOT));
+        assert!(!owner.is(ExtentOwner::CURRENT) && !owner.is(ExtentOwner::SHARED));
+    }
+
+    #[test]
+    fn extent_set_sharing() {
+        let set = ExtentSet::new(None);
+        assert!(set.insert(1 << 20, (1, 257), 4096, false));
+        // the same inode number in a snapshot is another file
+        assert!(!set.insert(1 << 20, (2, 257), 4096, true));
+        assert!(set.insert(2 << 20, (1, 257), 8192, false));
+        // the file referencing its own extent again
+        assert!(!set.insert(2 << 20, (1, 257), 8192, false));
+        let sharing = set.sharing();
+        assert_eq!((sharing.shared, sharing.n_shared), (4096, 1));
+        assert_eq!(sharing.exclusive, 8192);
+        assert_eq!(
+            (sharing.snapshot_shared, sharing.current_only),
+            (4096, 8192)
+        );
+    }
+
+    #[test]
+    fn io_error_kind_behind_compviz_error() {
+        let not_found = || io::Error::from(io::ErrorKind::NotFound);
+        for err in [
+            anyhow::Error::new(not_found()),
+            anyhow::Error::new(CompvizError::from(not_found())),
+            anyhow::Error::new(CompvizError::from(not_found())).context("search failed"),
+        ] {
+            assert_eq!(
+                io_error_kind(&err),
+                Some(io::ErrorKind::NotFound),
+                "{err:?}"
+            );
+        }
+        let denied = CompvizError::from(io::Error::from(io::ErrorKind::PermissionDenied));
+        assert_eq!(
+            io_error_kind(&denied.into()),
+            Some(io::ErrorKind::PermissionDenied)
+        );
+        assert_eq!(io_error_kind(&CompvizError::NotBtrfs.into()), None);
+    }
+}
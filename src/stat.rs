@@ -0,0 +1,383 @@
+use core::fmt;
+use std::{collections::BTreeMap, ops::AddAssign};
+
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ExtentInfo {
+    pub disk_bytes: usize,
+    pub uncompressed_bytes: usize,
+    pub referenced_bytes: usize,
+    /// Regular extents counted towards the disk bytes, like [Statistic::n_extents].
+    pub n_extents: usize,
+    /// References to regular extents, like [Statistic::n_refs].
+    pub n_refs: usize,
+}
+impl ExtentInfo {
+    /// 0 if nothing was read, rather than NaN.
+    pub fn compression_percent(&self) -> f64 {
+        if self.uncompressed_bytes == 0 {
+            return 0.0;
+        }
+        (self.disk_bytes as f64 / self.uncompressed_bytes as f64) * 100.0
+    }
+    /// Compression factor, uncompressed to disk bytes. 0 if nothing is on disk, rather than inf.
+    pub fn ratio(&self) -> f64 {
+        if self.disk_bytes == 0 {
+            return 0.0;
+        }
+        self.uncompressed_bytes as f64 / self.disk_bytes as f64
+    }
+    /// Bytes reclaimed by compression, 0 if compression didn't help at all.
+    pub fn saved(&self) -> usize {
+        self.uncompressed_bytes.saturating_sub(self.disk_bytes)
+    }
+}
+
+impl AddAssign<&ExtentInfo> for ExtentInfo {
+    fn add_assign(&mut self, rhs: &ExtentInfo) {
+        // destructured, so a new field fails to compile until it's summed here too
+        let ExtentInfo {
+            disk_bytes,
+            uncompressed_bytes,
+            referenced_bytes,
+            n_extents,
+            n_refs,
+        } = rhs;
+        self.disk_bytes += disk_bytes;
+        self.uncompressed_bytes += uncompressed_bytes;
+        self.referenced_bytes += referenced_bytes;
+        self.n_extents += n_extents;
+        self.n_refs += n_refs;
+    }
+}
+
+/// Compression algorithm of an extent, as stored in `btrfs_file_extent_item::compression`.
+///
+/// Ordered by the on-disk value, so sorting gives the same order as compsize's table.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(into = "String", try_from = "String")]
+pub enum CompressionType {
+    None,
+    Zlib,
+    Lzo,
+    Zstd,
+    Unknown(u8),
+}
+impl CompressionType {
+    pub fn iter() -> impl Iterator<Item = CompressionType> {
+        (u8::MIN..=u8::MAX).map(CompressionType::from)
+    }
+}
+impl From<u8> for CompressionType {
+    fn from(v: u8) -> Self {
+        match v {
+            0 => CompressionType::None,
+            1 => CompressionType::Zlib,
+            2 => CompressionType::Lzo,
+            3 => CompressionType::Zstd,
+            _ => CompressionType::Unknown(v),
+        }
+    }
+}
+impl From<CompressionType> for u8 {
+    fn from(c: CompressionType) -> Self {
+        match c {
+            CompressionType::None => 0,
+            CompressionType::Zlib => 1,
+            CompressionType::Lzo => 2,
+            CompressionType::Zstd => 3,
+            CompressionType::Unknown(v) => v,
+        }
+    }
+}
+impl fmt::Display for CompressionType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}",
+            match self {
+                CompressionType::None => "none",
+                CompressionType::Zlib => "zlib",
+                CompressionType::Lzo => "lzo",
+                CompressionType::Zstd => "zstd",
+                CompressionType::Unknown(v) => return write!(f, "unknown({})", v),
+            }
+        )
+    }
+}
+impl From<CompressionType> for String {
+    fn from(c: CompressionType) -> Self {
+        c.to_string()
+    }
+}
+impl TryFrom<String> for CompressionType {
+    type Error = String;
+    /// The inverse of [fmt::Display], so names in a dumped statistic can be read back.
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        Ok(match s.as_str() {
+            "none" => CompressionType::None,
+            "zlib" => CompressionType::Zlib,
+            "lzo" => CompressionType::Lzo,
+            "zstd" => CompressionType::Zstd,
+            _ => s
+                .strip_prefix("unknown(")
+                .and_then(|s| s.strip_suffix(')'))
+                .and_then(|s| s.parse::<u8>().ok())
+                .map(CompressionType::from)
+                .ok_or_else(|| format!("unknown compression type {s}"))?,
+        })
+    }
+}
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Statistic {
+    /// Ordered by compression type, so every output lists them in the same order.
+    pub extent_info: BTreeMap<CompressionType, ExtentInfo>,
+    pub n_files: usize,
+    pub n_extents: usize,
+    pub n_refs: usize,
+    pub n_inline: usize,
+    /// Bytes of the inline extents alone, they are part of their compression type's bytes too.
+    pub inline: ExtentInfo,
+    /// References to preallocated extents, e.g. from `fallocate(2)`.
+    pub n_prealloc: usize,
+    /// Bytes of the preallocated extents alone. They hold no data but reserve disk space,
+    /// so they're part of the `none` bytes too, like compsize and `df` count them,
+    /// unless left out by [crate::AnalyzeOptions::no_prealloc].
+    pub prealloc: ExtentInfo,
+    /// References to extents with a non-zero `encryption` field.
+    ///
+    /// btrfs doesn't write any as of now, their sizes may not mean the same as for others.
+    pub n_encrypted: usize,
+    /// Disk bytes of the encrypted extents, they're part of their compression type's bytes too.
+    pub encrypted_bytes: usize,
+    /// Regular extents with a zero `disk_bytenr`, i.e. holes of sparse files.
+    pub n_holes: usize,
+    pub hole_bytes: usize,
+    /// Sockets, FIFOs and device nodes, which have no extents.
+    pub n_special: usize,
+    /// Entries that were listed but gone by the time they were read.
+    pub n_vanished: usize,
+    /// Disk bytes of extents by generation age, see [GENERATION_AGE_BUCKETS].
+    pub generation_age: [usize; GENERATION_AGE_BUCKETS],
+    /// Disk bytes of extents referenced by a single file of the scan,
+    /// i.e. what deleting the scanned files would free.
+    ///
+    /// Like [Self::shared_bytes] it's only known for a whole scan, per-file statistics leave it 0.
+    pub exclusive_bytes: usize,
+    /// Disk bytes of extents referenced by several files of the scan, e.g. reflinked copies.
+    pub shared_bytes: usize,
+    /// Number of extents referenced by several files, the reflinked ones behind [Self::shared_bytes].
+    pub n_shared_extents: usize,
+    /// With snapshots scanned along, disk bytes of extents referenced only from the given paths,
+    /// i.e. what deleting them frees while the snapshots are kept.
+    ///
+    /// Like [Self::exclusive_bytes], these are only known for a whole scan.
+    pub current_only_bytes: usize,
+    /// Disk bytes of extents referenced both from the given paths and the snapshots.
+    pub snapshot_shared_bytes: usize,
+    /// Disk bytes of extents referenced only from the snapshots,
+    /// i.e. what deleting the snapshots frees.
+    pub snapshot_only_bytes: usize,
+    /// The generation filter the extents were counted with, if any.
+    pub since_generation: Option<u64>,
+    /// The estimated share of extents missed if they were deduplicated approximately,
+    /// see [crate::ApproxDedup].
+    pub approx_dedup_fp_rate: Option<f64>,
+    /// Number of extents by their disk size, see [EXTENT_SIZE_BUCKETS].
+    pub extent_sizes: [usize; EXTENT_SIZE_BUCKETS],
+    /// Number of files by how many extents they reference, see [FRAGMENTATION_BUCKET_BOUNDS].
+    pub fragmentation: [usize; FRAGMENTATION_BUCKETS],
+    /// Number of files by their compression ratio, see [RATIO_BUCKET_BOUNDS].
+    pub ratio_files: [usize; RATIO_BUCKETS],
+    /// Disk bytes of files by their compression ratio, see [RATIO_BUCKET_BOUNDS].
+    pub ratio_bytes: [usize; RATIO_BUCKETS],
+}
+/// Extents are bucketed by `fs generation - extent generation` in powers of ten,
+/// i.e. bucket `i` holds ages in `10^i..10^(i+1)`, the last bucket holds everything older.
+pub const GENERATION_AGE_BUCKETS: usize = 7;
+pub(crate) fn generation_age_bucket(age: u64) -> usize {
+    (age.checked_ilog10().unwrap_or(0) as usize).min(GENERATION_AGE_BUCKETS - 1)
+}
+
+/// Regular extents are bucketed by their disk bytes in powers of two from
+/// [MIN_BUCKETED_EXTENT_SIZE] on, i.e. bucket `i` holds sizes in `MIN << i..MIN << (i + 1)`,
+/// the first one everything smaller and the last one everything larger.
+/// The largest extent btrfs writes is 128MiB.
+pub const EXTENT_SIZE_BUCKETS: usize = 16;
+pub const MIN_BUCKETED_EXTENT_SIZE: usize = 4096;
+pub(crate) fn extent_size_bucket(disk_bytes: u64) -> usize {
+    (disk_bytes.max(1).ilog2() as usize)
+        .saturating_sub(MIN_BUCKETED_EXTENT_SIZE.ilog2() as usize)
+        .min(EXTENT_SIZE_BUCKETS - 1)
+}
+
+/// Files are bucketed by their number of extent references, inline ones included,
+/// bucket `i` holding counts from the bound `i - 1` on. Bucket 0 holds files without any
+/// extent, e.g. empty ones, and the last one every count at or above the last bound.
+pub const FRAGMENTATION_BUCKET_BOUNDS: [usize; 4] = [1, 2, 11, 101];
+pub const FRAGMENTATION_BUCKETS: usize = FRAGMENTATION_BUCKET_BOUNDS.len() + 1;
+pub(crate) fn fragmentation_bucket(extents: usize) -> usize {
+    FRAGMENTATION_BUCKET_BOUNDS
+        .iter()
+        .take_while(|&&b| extents >= b)
+        .count()
+}
+
+/// Files are bucketed by their [ExtentInfo::ratio] between these bounds. Bucket 0 holds files
+/// without disk or referenced bytes, e.g. empty ones, bucket 1 those below the first bound,
+/// and the last one those at or above the last bound.
+pub const RATIO_BUCKET_BOUNDS: [f64; 4] = [1.1, 1.5, 2.0, 4.0];
+pub const RATIO_BUCKETS: usize = RATIO_BUCKET_BOUNDS.len() + 2;
+pub(crate) fn ratio_bucket(info: &ExtentInfo) -> usize {
+    if info.disk_bytes == 0 || info.referenced_bytes == 0 {
+        return 0;
+    }
+    let ratio = info.ratio();
+    1 + RATIO_BUCKET_BOUNDS
+        .iter()
+        .take_while(|&&b| ratio >= b)
+        .count()
+}
+
+impl Statistic {
+    /// All compression types summed up, i.e. the TOTAL row.
+    pub fn total(&self) -> ExtentInfo {
+        self.extent_info
+            .values()
+            .fold(ExtentInfo::default(), |mut acc, e| {
+                acc += e;
+                acc
+            })
+    }
+    /// Mean disk bytes of the regular extents, 0 if there are none.
+    pub fn average_extent_size(&self) -> usize {
+        let regular = self.total().disk_bytes - self.inline.disk_bytes;
+        regular.checked_div(self.n_extents).unwrap_or(0)
+    }
+    /// The [EXTENT_SIZE_BUCKETS] bucket holding the median extent,
+    /// `None` if [Self::extent_sizes] wasn't collected.
+    pub fn median_extent_size_bucket(&self) -> Option<usize> {
+        let n: usize = self.extent_sizes.iter().sum();
+        let mut below = 0;
+        self.extent_sizes.iter().position(|&count| {
+            below += count;
+            n > 0 && below * 2 >= n
+        })
+    }
+    /// The compression type holding the most referenced bytes.
+    pub fn dominant_compression(&self) -> Option<CompressionType> {
+        self.extent_info
+            .iter()
+            .max_by_key(|(_, info)| info.referenced_bytes)
+            .map(|(c, _)| *c)
+    }
+}
+impl AddAssign<&Statistic> for Statistic {
+    fn add_assign(&mut self, rhs: &Statistic) {
+        self.n_files += rhs.n_files;
+        self.n_extents += rhs.n_extents;
+        self.n_refs += rhs.n_refs;
+        self.n_inline += rhs.n_inline;
+        self.n_holes += rhs.n_holes;
+        self.hole_bytes += rhs.hole_bytes;
+        self.inline += &rhs.inline;
+        self.n_encrypted += rhs.n_encrypted;
+        self.encrypted_bytes += rhs.encrypted_bytes;
+        self.n_prealloc += rhs.n_prealloc;
+        self.prealloc += &rhs.prealloc;
+        self.n_special += rhs.n_special;
+        self.since_generation = self.since_generation.or(rhs.since_generation);
+        self.approx_dedup_fp_rate = self.approx_dedup_fp_rate.or(rhs.approx_dedup_fp_rate);
+        self.exclusive_bytes += rhs.exclusive_bytes;
+        self.shared_bytes += rhs.shared_bytes;
+        self.n_shared_extents += rhs.n_shared_extents;
+        self.current_only_bytes += rhs.current_only_bytes;
+        self.snapshot_shared_bytes += rhs.snapshot_shared_bytes;
+        self.snapshot_only_bytes += rhs.snapshot_only_bytes;
+        self.n_vanished += rhs.n_vanished;
+        for (age, rhs_age) in self.generation_age.iter_mut().zip(rhs.generation_age) {
+            *age += rhs_age;
+        }
+        for (count, rhs_count) in self.extent_sizes.iter_mut().zip(rhs.extent_sizes) {
+            *count += rhs_count;
+        }
+        for (files, rhs_files) in self.fragmentation.iter_mut().zip(rhs.fragmentation) {
+            *files += rhs_files;
+        }
+        for (files, rhs_files) in self.ratio_files.iter_mut().zip(rhs.ratio_files) {
+            *files += rhs_files;
+        }
+        for (bytes, rhs_bytes) in self.ratio_bytes.iter_mut().zip(rhs.ratio_bytes) {
+            *bytes += rhs_bytes;
+        }
+        for (compression, info) in rhs.extent_info.iter() {
+            *self.extent_info.entry(*compression).or_default() += info;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn extent_info_add_assign() {
+        let mut info = ExtentInfo {
+            disk_bytes: 1,
+            uncompressed_bytes: 2,
+            referenced_bytes: 3,
+            n_extents: 4,
+            n_refs: 5,
+        };
+        info += &ExtentInfo {
+            disk_bytes: 10,
+            uncompressed_bytes: 20,
+            referenced_bytes: 30,
+            n_extents: 40,
+            n_refs: 50,
+        };
+        let ExtentInfo {
+            disk_bytes,
+            uncompressed_bytes,
+            referenced_bytes,
+            n_extents,
+            n_refs,
+        } = info;
+        assert_eq!(
+            [
+                disk_bytes,
+                uncompressed_bytes,
+                referenced_bytes,
+                n_extents,
+                n_refs
+            ],
+            [11, 22, 33, 44, 55]
+        );
+    }
+
+    #[test]
+    fn compression_type_string_round_trip() {
+        for c in CompressionType::iter() {
+            assert_eq!(CompressionType::try_from(String::from(c)), Ok(c));
+        }
+        for s in ["", "gzip", "Zstd", "unknown(256)", "unknown(1"] {
+            assert!(
+                CompressionType::try_from(s.to_string()).is_err(),
+                "{s:?} parsed"
+            );
+        }
+    }
+
+    #[test]
+    fn compression_type_serde() {
+        let json = serde_json::to_string(&[CompressionType::Zstd, CompressionType::Unknown(7)]);
+        assert_eq!(json.unwrap(), r#"["zstd","unknown(7)"]"#);
+        let parsed: Vec<CompressionType> =
+            serde_json::from_str(r#"["none","unknown(7)"]"#).unwrap();
+        assert_eq!(parsed, [CompressionType::None, CompressionType::Unknown(7)]);
+        assert!(serde_json::from_str::<CompressionType>(r#""gzip""#).is_err());
+    }
+}
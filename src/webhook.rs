@@ -0,0 +1,54 @@
+use std::{
+    path::PathBuf,
+    process,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::anyhow;
+use serde::Serialize;
+
+use compviz::Statistic;
+
+/// The JSON body POSTed to `--webhook`.
+#[derive(Debug, Serialize)]
+struct Payload<'a> {
+    run_id: String,
+    /// Seconds since the unix epoch at which the report was sent.
+    timestamp: u64,
+    /// Lossy, as JSON strings can't hold paths that aren't UTF-8.
+    paths: Vec<String>,
+    statistic: &'a Statistic,
+}
+
+/// POST `stat` to `url`, with each of `headers` given as `Name: value`.
+///
+/// Non-2xx responses are reported as errors, it's up to the caller whether they are fatal.
+pub fn send(
+    url: &str,
+    headers: &[String],
+    paths: &[PathBuf],
+    stat: &Statistic,
+) -> anyhow::Result<()> {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH)?;
+    let payload = Payload {
+        run_id: format!("{:x}-{:x}", now.as_nanos(), process::id()),
+        timestamp: now.as_secs(),
+        paths: paths
+            .iter()
+            .map(|path| path.to_string_lossy().into_owned())
+            .collect(),
+        statistic: stat,
+    };
+    let mut request = ureq::post(url);
+    for header in headers {
+        let (name, value) = header
+            .split_once(':')
+            .ok_or_else(|| anyhow!("invalid webhook header {header:?}, expected `Name: value`"))?;
+        request = request.set(name.trim(), value.trim());
+    }
+    match request.send_json(&payload) {
+        Ok(_) => Ok(()),
+        Err(ureq::Error::Status(code, _)) => Err(anyhow!("webhook {url} responded with {code}")),
+        Err(ureq::Error::Transport(err)) => Err(anyhow!("webhook {url} failed: {err}")),
+    }
+}
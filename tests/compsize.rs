@@ -0,0 +1,47 @@
+use compviz::{
+    format::{CompsizeFormatter, Formatter},
+    CompressionType, ExtentInfo, Statistic,
+};
+
+fn info(disk_bytes: usize, uncompressed_bytes: usize, referenced_bytes: usize) -> ExtentInfo {
+    ExtentInfo {
+        disk_bytes,
+        uncompressed_bytes,
+        referenced_bytes,
+        ..Default::default()
+    }
+}
+
+#[test]
+fn same_output_as_compsize() {
+    let mut stat = Statistic {
+        n_files: 3,
+        n_extents: 9,
+        n_refs: 11,
+        n_inline: 1,
+        ..Default::default()
+    };
+    stat.extent_info = [
+        (CompressionType::None, info(4096, 4096, 4096)),
+        // 29.0 / 100.0 * 100.0 is 28.99… in floats
+        (CompressionType::Zlib, info(29, 100, 100)),
+        // compsize leaves out types without data
+        (CompressionType::Lzo, info(0, 0, 0)),
+        (CompressionType::Zstd, info(1 << 20, 4 << 20, 6 << 20)),
+        (
+            CompressionType::Unknown(7),
+            info(64 << 10, 64 << 10, 64 << 10),
+        ),
+    ]
+    .into_iter()
+    .collect();
+
+    let mut out = Vec::new();
+    CompsizeFormatter::default()
+        .format(&stat, &mut out)
+        .unwrap();
+    assert_eq!(
+        String::from_utf8(out).unwrap(),
+        include_str!("fixtures/compsize.txt")
+    );
+}